@@ -1,12 +1,20 @@
 use arccstr::ArcCStr;
 
-use chrono::{self, NaiveDateTime};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
 
-use nom_sql::Literal;
+use nom_sql::{Literal, Real, SqlType};
 
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+use std::cmp;
+use std::convert::TryFrom;
+use std::error::Error;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::ops::{Add, Deref, DerefMut, Div, Mul, Sub};
+use std::ptr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
 const FLOAT_PRECISION: f64 = 1000_000_000.0;
 const TINYTEXT_WIDTH: usize = 15;
@@ -37,6 +45,61 @@ pub enum DataType {
     TinyText([u8; TINYTEXT_WIDTH]),
     /// A timestamp for date/time types.
     Timestamp(NaiveDateTime),
+    /// A timestamp in UTC, for timestamps that must mean the same instant on every machine.
+    ///
+    /// Compares equal to the `Timestamp` of the same time in UTC.
+    TimestampTz(DateTime<Utc>),
+    /// A reference-counted binary value.
+    ByteArray(Arc<Vec<u8>>),
+    /// A boolean value.
+    Bool(bool),
+    /// A date without a time of day.
+    Date(NaiveDate),
+    /// A signed duration in microseconds, for time types.
+    Time(i64),
+    /// A reference-counted 128-bit UUID.
+    Uuid(Arc<[u8; 16]>),
+    /// A reference-counted exact decimal value.
+    Decimal(Arc<Decimal>),
+}
+
+// Formats a duration in microseconds the way SQL time values are written, e.g. -838:59:59.5.
+fn fmt_time(micros: i64, f: &mut fmt::Formatter) -> fmt::Result {
+    let sign = if micros < 0 { "-" } else { "" };
+    let micros = (micros as i128).abs();
+    let secs = micros / 1_000_000;
+    write!(
+        f,
+        "{}{:02}:{:02}:{:02}",
+        sign,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )?;
+    if micros % 1_000_000 != 0 {
+        write!(f, ".{:06}", micros % 1_000_000)?;
+    }
+    Ok(())
+}
+
+// Formats a UUID in the usual hyphenated form.
+fn fmt_uuid(bytes: &[u8; 16], f: &mut fmt::Formatter) -> fmt::Result {
+    for (i, b) in bytes.iter().enumerate() {
+        if i == 4 || i == 6 || i == 8 || i == 10 {
+            write!(f, "-")?;
+        }
+        write!(f, "{:02x}", b)?;
+    }
+    Ok(())
+}
+
+// Formats bytes the way a SQL blob literal is written.
+fn fmt_bytes(bytes: &[u8], f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "x'")?;
+    for b in bytes {
+        write!(f, "{:02x}", b)?;
+    }
+    write!(f, "'")
 }
 
 impl DataType {
@@ -44,7 +107,7 @@ impl DataType {
         match *self {
             DataType::None => String::from("*"),
             DataType::Text(..) | DataType::TinyText(..) => {
-                let text: Cow<str> = self.into();
+                let text = self.text().unwrap_or_default();
                 format!("{}", text)
             }
             DataType::Int(n) => format!("{}", n),
@@ -57,7 +120,14 @@ impl DataType {
                     format!("{}", format!("{}.{:09}", i, frac.abs()))
                 }
             }
-            DataType::Timestamp(ts) => format!("{}", format!("{}", ts.format("%c"))),
+            DataType::Timestamp(..)
+            | DataType::TimestampTz(..)
+            | DataType::ByteArray(..)
+            | DataType::Bool(..)
+            | DataType::Date(..)
+            | DataType::Time(..)
+            | DataType::Uuid(..)
+            | DataType::Decimal(..) => format!("{}", self),
         }
     }
 }
@@ -70,42 +140,125 @@ impl DataType {
     pub fn deep_clone(&self) -> Self {
         match *self {
             DataType::Text(ref cstr) => DataType::Text(ArcCStr::from(&**cstr)),
+            DataType::ByteArray(ref bytes) => DataType::ByteArray(Arc::new((**bytes).clone())),
+            DataType::Uuid(ref bytes) => DataType::Uuid(Arc::new(**bytes)),
+            DataType::Decimal(ref d) => DataType::Decimal(Arc::new((**d).clone())),
             ref dt => dt.clone(),
         }
     }
+
+    // The name of this value's variant, for error messages.
+    fn type_name(&self) -> &'static str {
+        match *self {
+            DataType::None => "None",
+            DataType::Int(..) => "Int",
+            DataType::BigInt(..) => "BigInt",
+            DataType::Real(..) => "Real",
+            DataType::Text(..) | DataType::TinyText(..) => "Text",
+            DataType::Timestamp(..) => "Timestamp",
+            DataType::TimestampTz(..) => "TimestampTz",
+            DataType::ByteArray(..) => "ByteArray",
+            DataType::Bool(..) => "Bool",
+            DataType::Date(..) => "Date",
+            DataType::Time(..) => "Time",
+            DataType::Uuid(..) => "Uuid",
+            DataType::Decimal(..) => "Decimal",
+        }
+    }
+
+    // Where values of this variant sort relative to values of other variants.
+    fn rank(&self) -> u8 {
+        match *self {
+            DataType::Bool(..) => 0,
+            DataType::Int(..) | DataType::BigInt(..) => 1,
+            DataType::Real(..) => 2,
+            DataType::Decimal(..) => 3,
+            DataType::Text(..) | DataType::TinyText(..) => 4,
+            DataType::Date(..) => 5,
+            DataType::Time(..) => 6,
+            DataType::Timestamp(..) | DataType::TimestampTz(..) => 7,
+            DataType::ByteArray(..) => 8,
+            DataType::Uuid(..) => 9,
+            DataType::None => 10,
+        }
+    }
 }
 
-impl PartialEq for DataType {
-    fn eq(&self, other: &DataType) -> bool {
-        unsafe {
-            use std::{mem, slice};
-            // if the two datatypes are byte-for-byte identical, they're the same.
-            let a: &[u8] = slice::from_raw_parts(mem::transmute(self), mem::size_of::<Self>());
-            let b: &[u8] = slice::from_raw_parts(mem::transmute(other), mem::size_of::<Self>());
-            if a == b {
-                return true;
+/// The error returned when a value can't be converted to or from a `DataType`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataTypeError {
+    /// The `DataType` is of a variant that doesn't convert to the target type.
+    WrongType {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// The value doesn't fit in the target type.
+    OutOfRange,
+    /// The value is NaN or infinite, which a `DataType` can't hold.
+    NotFinite,
+    /// The string contains a NUL byte, which a `DataType` can't hold.
+    InteriorNul,
+    /// The result of an arithmetic operation doesn't fit in a `DataType`.
+    Overflow,
+    /// An arithmetic operation divided by zero.
+    DivisionByZero,
+    /// The literal is a placeholder, which has no value until it is bound.
+    Placeholder,
+    /// The string isn't a valid value of the target type.
+    Parse { expected: &'static str },
+}
+
+impl fmt::Display for DataTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DataTypeError::WrongType { expected, found } => {
+                write!(f, "expected a {} value, found a {} value", expected, found)
             }
+            DataTypeError::OutOfRange => write!(f, "value out of range"),
+            DataTypeError::NotFinite => write!(f, "value is not finite"),
+            DataTypeError::InteriorNul => write!(f, "string contains a NUL byte"),
+            DataTypeError::Overflow => write!(f, "arithmetic overflow"),
+            DataTypeError::DivisionByZero => write!(f, "division by zero"),
+            DataTypeError::Placeholder => write!(f, "placeholder has no value"),
+            DataTypeError::Parse { expected } => write!(f, "can't parse as a {} value", expected),
         }
+    }
+}
+
+impl Error for DataTypeError {}
 
+impl PartialEq for DataType {
+    fn eq(&self, other: &DataType) -> bool {
+        // values that share their reference-counted data are the same without looking at it
         match (self, other) {
-            (&DataType::Text(ref a), &DataType::Text(ref b)) => a == b,
+            (&DataType::Text(ref a), &DataType::Text(ref b)) => {
+                ptr::eq(a.as_ptr(), b.as_ptr()) || a == b
+            }
             (&DataType::TinyText(ref a), &DataType::TinyText(ref b)) => a == b,
             (&DataType::Text(..), &DataType::TinyText(..))
             | (&DataType::TinyText(..), &DataType::Text(..)) => {
-                let a: Cow<str> = self.into();
-                let b: Cow<str> = other.into();
+                let a = self.text().unwrap_or_default();
+                let b = other.text().unwrap_or_default();
                 a == b
             }
             (&DataType::BigInt(a), &DataType::BigInt(b)) => a == b,
             (&DataType::Int(a), &DataType::Int(b)) => a == b,
-            (&DataType::BigInt(..), &DataType::Int(..))
-            | (&DataType::Int(..), &DataType::BigInt(..)) => {
-                let a: i64 = self.into();
-                let b: i64 = other.into();
-                a == b
-            }
+            (&DataType::BigInt(a), &DataType::Int(b)) => a == b as i64,
+            (&DataType::Int(a), &DataType::BigInt(b)) => a as i64 == b,
             (&DataType::Real(ai, af), &DataType::Real(bi, bf)) => ai == bi && af == bf,
             (&DataType::Timestamp(tsa), &DataType::Timestamp(tsb)) => tsa == tsb,
+            (&DataType::TimestampTz(tsa), &DataType::TimestampTz(tsb)) => tsa == tsb,
+            // timestamps without a time zone are in UTC
+            (&DataType::Timestamp(tsa), &DataType::TimestampTz(tsb)) => tsa == tsb.naive_utc(),
+            (&DataType::TimestampTz(tsa), &DataType::Timestamp(tsb)) => tsa.naive_utc() == tsb,
+            (&DataType::ByteArray(ref a), &DataType::ByteArray(ref b)) => {
+                Arc::ptr_eq(a, b) || a == b
+            }
+            (&DataType::Bool(a), &DataType::Bool(b)) => a == b,
+            (&DataType::Date(a), &DataType::Date(b)) => a == b,
+            (&DataType::Time(a), &DataType::Time(b)) => a == b,
+            (&DataType::Uuid(ref a), &DataType::Uuid(ref b)) => Arc::ptr_eq(a, b) || a == b,
+            (&DataType::Decimal(ref a), &DataType::Decimal(ref b)) => a == b,
             (&DataType::None, &DataType::None) => true,
 
             _ => false,
@@ -127,34 +280,66 @@ impl Ord for DataType {
             (&DataType::TinyText(ref a), &DataType::TinyText(ref b)) => a.cmp(b),
             (&DataType::Text(..), &DataType::TinyText(..))
             | (&DataType::TinyText(..), &DataType::Text(..)) => {
-                let a: Cow<str> = self.into();
-                let b: Cow<str> = other.into();
+                let a = self.text().unwrap_or_default();
+                let b = other.text().unwrap_or_default();
                 a.cmp(&b)
             }
             (&DataType::BigInt(a), &DataType::BigInt(ref b)) => a.cmp(b),
             (&DataType::Int(a), &DataType::Int(b)) => a.cmp(&b),
-            (&DataType::BigInt(..), &DataType::Int(..))
-            | (&DataType::Int(..), &DataType::BigInt(..)) => {
-                let a: i64 = self.into();
-                let b: i64 = other.into();
-                a.cmp(&b)
-            }
+            (&DataType::BigInt(a), &DataType::Int(b)) => a.cmp(&(b as i64)),
+            (&DataType::Int(a), &DataType::BigInt(b)) => (a as i64).cmp(&b),
             (&DataType::Real(ai, af), &DataType::Real(ref bi, ref bf)) => {
                 ai.cmp(bi).then_with(|| af.cmp(bf))
             }
             (&DataType::Timestamp(tsa), &DataType::Timestamp(ref tsb)) => tsa.cmp(tsb),
+            (&DataType::TimestampTz(tsa), &DataType::TimestampTz(ref tsb)) => tsa.cmp(tsb),
+            (&DataType::Timestamp(tsa), &DataType::TimestampTz(tsb)) => tsa.cmp(&tsb.naive_utc()),
+            (&DataType::TimestampTz(tsa), &DataType::Timestamp(tsb)) => tsa.naive_utc().cmp(&tsb),
+            (&DataType::ByteArray(ref a), &DataType::ByteArray(ref b)) => a.cmp(b),
+            (&DataType::Bool(a), &DataType::Bool(b)) => a.cmp(&b),
+            (&DataType::Date(a), &DataType::Date(ref b)) => a.cmp(b),
+            (&DataType::Time(a), &DataType::Time(b)) => a.cmp(&b),
+            (&DataType::Uuid(ref a), &DataType::Uuid(ref b)) => a.cmp(b),
+            (&DataType::Decimal(ref a), &DataType::Decimal(ref b)) => a.cmp(b),
             (&DataType::None, &DataType::None) => Ordering::Equal,
 
-            // order Ints, Reals, Text, Timestamps, None
-            (&DataType::Int(..), _) | (&DataType::BigInt(..), _) => Ordering::Greater,
-            (&DataType::Real(..), _) => Ordering::Greater,
-            (&DataType::Text(..), _) | (&DataType::TinyText(..), _) => Ordering::Greater,
-            (&DataType::Timestamp(..), _) => Ordering::Greater,
-            (&DataType::None, _) => Ordering::Greater,
+            // order Bools, Ints, Reals, Decimals, Text, Dates, Times, Timestamps (with or without
+            // a time zone), ByteArrays, Uuids, None
+            _ => self.rank().cmp(&other.rank()),
         }
     }
 }
 
+impl DataType {
+    /// Compare two values the way SQL does.
+    ///
+    /// Unlike `Ord`, which gives every pair of values a place so they can be used as keys, this
+    /// returns `None` (SQL's unknown) when either value is `None` or when the values are of
+    /// types that can't be compared. Numeric values of different types are compared by value.
+    pub fn sql_cmp(&self, other: &DataType) -> Option<Ordering> {
+        match (self, other) {
+            (&DataType::None, _) | (_, &DataType::None) => None,
+            (&DataType::Decimal(..), _) | (_, &DataType::Decimal(..)) => {
+                match (to_decimal(self), to_decimal(other)) {
+                    (Some(a), Some(b)) => Some(a.cmp(&b)),
+                    _ => None,
+                }
+            }
+            _ => match (to_fixed(self), to_fixed(other)) {
+                (Some(a), Some(b)) => Some(a.cmp(&b)),
+                _ if self.rank() == other.rank() => Some(self.cmp(other)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Whether two values are equal under SQL semantics, so `None` is never equal to anything,
+    /// including `None`. See `sql_cmp`.
+    pub fn sql_eq(&self, other: &DataType) -> Option<bool> {
+        self.sql_cmp(other).map(|o| o == Ordering::Equal)
+    }
+}
+
 impl Hash for DataType {
     fn hash<H: Hasher>(&self, state: &mut H) {
         // The default derived hash function also hashes the variant tag, which turns out to be
@@ -162,19 +347,24 @@ impl Hash for DataType {
         // collisions, but the decreased overhead is worth it.
         match *self {
             DataType::None => {}
-            DataType::Int(..) | DataType::BigInt(..) => {
-                let n: i64 = self.into();
-                n.hash(state)
-            }
+            DataType::Int(n) => (n as i64).hash(state),
+            DataType::BigInt(n) => n.hash(state),
             DataType::Real(i, f) => {
                 i.hash(state);
                 f.hash(state);
             }
             DataType::Text(..) | DataType::TinyText(..) => {
-                let t: Cow<str> = self.into();
+                let t = self.text().unwrap_or_default();
                 t.hash(state)
             }
             DataType::Timestamp(ts) => ts.hash(state),
+            DataType::TimestampTz(ts) => ts.naive_utc().hash(state),
+            DataType::ByteArray(ref bytes) => bytes.hash(state),
+            DataType::Bool(b) => b.hash(state),
+            DataType::Date(d) => d.hash(state),
+            DataType::Time(t) => t.hash(state),
+            DataType::Uuid(ref bytes) => bytes.hash(state),
+            DataType::Decimal(ref d) => d.hash(state),
         }
     }
 }
@@ -192,15 +382,30 @@ impl From<i32> for DataType {
 }
 
 impl From<usize> for DataType {
+    /// An `Int` if the value fits in one, or else a `BigInt`, or else a `Decimal`.
     fn from(s: usize) -> Self {
-        DataType::Int(s as i32)
+        if s <= i32::max_value() as usize {
+            DataType::Int(s as i32)
+        } else if s as u64 <= i64::max_value() as u64 {
+            DataType::BigInt(s as i64)
+        } else {
+            Decimal::fit(s as i128, 1, 0, Rounding::HalfUp)
+                .expect("a usize has fewer than MAX_PRECISION digits")
+                .into()
+        }
     }
 }
 
-impl From<f64> for DataType {
-    fn from(f: f64) -> Self {
+impl TryFrom<f64> for DataType {
+    type Error = DataTypeError;
+
+    fn try_from(f: f64) -> Result<Self, Self::Error> {
         if !f.is_finite() {
-            panic!();
+            return Err(DataTypeError::NotFinite);
+        }
+        // the integer part has to fit in an i64
+        if f.abs() >= 9_223_372_036_854_775_808.0 {
+            return Err(DataTypeError::OutOfRange);
         }
 
         let mut i = f.trunc() as i64;
@@ -213,121 +418,519 @@ impl From<f64> for DataType {
             frac = 0;
         }
 
-        DataType::Real(i, frac)
+        Ok(DataType::Real(i, frac))
+    }
+}
+
+impl From<bool> for DataType {
+    fn from(b: bool) -> Self {
+        DataType::Bool(b)
+    }
+}
+
+impl From<DateTime<Utc>> for DataType {
+    fn from(ts: DateTime<Utc>) -> Self {
+        DataType::TimestampTz(ts)
+    }
+}
+
+impl From<NaiveDate> for DataType {
+    fn from(d: NaiveDate) -> Self {
+        DataType::Date(d)
+    }
+}
+
+impl TryFrom<Duration> for DataType {
+    type Error = DataTypeError;
+
+    fn try_from(d: Duration) -> Result<Self, Self::Error> {
+        d.num_microseconds()
+            .map(DataType::Time)
+            .ok_or(DataTypeError::OutOfRange)
+    }
+}
+
+impl From<[u8; 16]> for DataType {
+    fn from(u: [u8; 16]) -> Self {
+        DataType::Uuid(Arc::new(u))
+    }
+}
+
+impl From<Vec<u8>> for DataType {
+    fn from(b: Vec<u8>) -> Self {
+        DataType::ByteArray(Arc::new(b))
+    }
+}
+
+// nom_sql keeps the digits after the decimal point as an integer, and loses any leading zeros,
+// so 1.5 and 1.05 both have a fractional part of 5. Only whole numbers can be read back exactly.
+fn from_fixed_point(integral: i32, fractional: i32) -> Result<DataType, DataTypeError> {
+    if fractional != 0 {
+        return Err(DataTypeError::OutOfRange);
+    }
+    Ok(DataType::Real(integral as i64, 0))
+}
+
+// Builds a nom_sql fixed point literal, which can only hold numbers whose fractional digits
+// don't start with a zero, and can't hold negative numbers between -1 and 0.
+fn fixed_point_literal(
+    negative: bool,
+    integral: i128,
+    fraction: &str,
+) -> Result<Literal, DataTypeError> {
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.starts_with('0')
+        || negative && integral == 0
+        || integral < i32::min_value() as i128
+        || integral > i32::max_value() as i128
+    {
+        return Err(DataTypeError::OutOfRange);
+    }
+    let fractional = if fraction.is_empty() {
+        0
+    } else {
+        fraction.parse().map_err(|_| DataTypeError::OutOfRange)?
+    };
+    Ok(Literal::FixedPoint(Real {
+        integral: integral as i32,
+        fractional: fractional,
+    }))
+}
+
+/// A source of the current time, so that tests can control what time it is.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system's clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when it is told to.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        ManualClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + by;
     }
 }
 
-impl<'a> From<&'a Literal> for DataType {
-    fn from(l: &'a Literal) -> Self {
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+impl DataType {
+    /// Convert a literal, taking the current time from the given clock.
+    ///
+    /// `CURRENT_TIMESTAMP` is a `TimestampTz`, and `CURRENT_DATE` and `CURRENT_TIME` are the
+    /// date and time of day in UTC, so the result doesn't depend on the machine's time zone.
+    /// Fixed point literals with a fractional part can't be read back exactly, and are
+    /// `OutOfRange`.
+    pub fn from_literal(l: &Literal, clock: &dyn Clock) -> Result<DataType, DataTypeError> {
         match *l {
-            Literal::Null => DataType::None,
-            Literal::Integer(i) => i.into(),
-            Literal::String(ref s) => s.as_str().into(),
-            Literal::CurrentTimestamp => {
-                let ts = chrono::Local::now().naive_local();
-                DataType::Timestamp(ts)
+            Literal::Null => Ok(DataType::None),
+            Literal::Integer(i) => Ok(i.into()),
+            Literal::UnsignedInteger(i) if i <= i64::max_value() as u64 => Ok((i as i64).into()),
+            Literal::UnsignedInteger(..) => Err(DataTypeError::OutOfRange),
+            Literal::FixedPoint(ref r) => from_fixed_point(r.integral, r.fractional),
+            Literal::String(ref s) => DataType::try_from(s.as_str()),
+            Literal::Blob(ref b) => Ok(b.clone().into()),
+            Literal::CurrentTimestamp => Ok(DataType::TimestampTz(clock.now())),
+            Literal::CurrentTime => {
+                let t = clock.now().time();
+                let secs = t.num_seconds_from_midnight() as i64;
+                Ok(DataType::Time(
+                    secs * 1_000_000 + t.nanosecond() as i64 / 1000,
+                ))
             }
-            Literal::FixedPoint(ref r) => DataType::Real(r.integral as i64, r.fractional as i32),
-            _ => unimplemented!(),
+            Literal::CurrentDate => Ok(DataType::Date(clock.now().naive_utc().date())),
+            Literal::Placeholder => Err(DataTypeError::Placeholder),
         }
     }
+
+    /// Convert a literal that is being stored in a column of the given type, like
+    /// `from_literal`.
+    ///
+    /// Numbers and strings stored in `DECIMAL` columns become `Decimal`s of the column's
+    /// precision and scale. Fixed point literals with a fractional part can't be converted
+    /// exactly (see `Decimal::try_from`), so they fail with `OutOfRange`.
+    pub fn from_literal_as(
+        l: &Literal,
+        ty: &SqlType,
+        clock: &dyn Clock,
+    ) -> Result<DataType, DataTypeError> {
+        let (precision, scale) = match *ty {
+            SqlType::Decimal(precision, scale) => (precision, scale),
+            _ => return DataType::from_literal(l, clock),
+        };
+        let d = match *l {
+            Literal::FixedPoint(ref r) => Decimal::try_from(r)?,
+            Literal::String(ref s) => s.parse::<Decimal>()?,
+            _ => match DataType::from_literal(l, clock)? {
+                DataType::None => return Ok(DataType::None),
+                ref n => Decimal::try_from(n)?,
+            },
+        };
+        d.rescale(precision, scale, Rounding::HalfUp)
+            .map(DataType::from)
+    }
 }
 
-impl From<Literal> for DataType {
-    fn from(l: Literal) -> Self {
+impl<'a> TryFrom<&'a Literal> for DataType {
+    type Error = DataTypeError;
+
+    fn try_from(l: &'a Literal) -> Result<Self, Self::Error> {
+        DataType::from_literal(l, &SystemClock)
+    }
+}
+
+impl TryFrom<Literal> for DataType {
+    type Error = DataTypeError;
+
+    fn try_from(l: Literal) -> Result<Self, Self::Error> {
         match l {
-            Literal::Null => DataType::None,
-            Literal::Integer(i) => i.into(),
-            Literal::String(s) => s.as_str().into(),
-            Literal::CurrentTimestamp => {
-                let ts = chrono::Local::now().naive_local();
-                DataType::Timestamp(ts)
+            Literal::String(s) => DataType::try_from(s),
+            Literal::Blob(b) => Ok(b.into()),
+            l => DataType::try_from(&l),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a DataType> for Literal {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        match *d {
+            DataType::None => Ok(Literal::Null),
+            DataType::Int(i) => Ok(Literal::Integer(i as i64)),
+            DataType::BigInt(i) => Ok(Literal::Integer(i)),
+            DataType::Real(i, f) => {
+                fixed_point_literal(i < 0 || f < 0, i as i128, &format!("{:09}", f.abs()))
+            }
+            DataType::Decimal(ref dec) => {
+                let scale = pow10(dec.scale as u32).unwrap();
+                let fraction = format!(
+                    "{:0width$}",
+                    (dec.mantissa % scale).abs(),
+                    width = dec.scale as usize
+                );
+                fixed_point_literal(dec.mantissa < 0, dec.mantissa / scale, &fraction)
+            }
+            DataType::Text(..) | DataType::TinyText(..) => {
+                Ok(Literal::String(String::try_from(d)?))
+            }
+            DataType::Timestamp(ts) => Ok(Literal::String(
+                ts.format("%Y-%m-%d %H:%M:%S%.f").to_string(),
+            )),
+            DataType::TimestampTz(ts) => Ok(Literal::String(ts.to_rfc3339())),
+            DataType::ByteArray(ref bytes) => Ok(Literal::Blob((**bytes).clone())),
+            DataType::Bool(b) => Ok(Literal::Integer(b as i64)),
+            DataType::Date(..) | DataType::Time(..) | DataType::Uuid(..) => {
+                Ok(Literal::String(format!("{}", d)))
             }
-            Literal::FixedPoint(r) => DataType::Real(r.integral as i64, r.fractional as i32),
-            _ => unimplemented!(),
         }
     }
 }
 
+impl TryFrom<DataType> for Literal {
+    type Error = DataTypeError;
+
+    fn try_from(d: DataType) -> Result<Self, Self::Error> {
+        Literal::try_from(&d)
+    }
+}
+
 use std::borrow::Cow;
-impl<'a> Into<Cow<'a, str>> for &'a DataType {
-    fn into(self) -> Cow<'a, str> {
+impl DataType {
+    // The text of a text value, or `None` for any other value.
+    fn text<'a>(&'a self) -> Option<Cow<'a, str>> {
         match *self {
-            DataType::Text(ref s) => s.to_string_lossy(),
+            DataType::Text(ref s) => Some(s.to_string_lossy()),
             DataType::TinyText(ref bts) => {
                 if bts[TINYTEXT_WIDTH - 1] == 0 {
                     // NULL terminated CStr
                     use std::ffi::CStr;
                     let null = bts.iter().position(|&i| i == 0).unwrap() + 1;
-                    CStr::from_bytes_with_nul(&bts[0..null])
-                        .unwrap()
-                        .to_string_lossy()
+                    Some(
+                        CStr::from_bytes_with_nul(&bts[0..null])
+                            .unwrap()
+                            .to_string_lossy(),
+                    )
                 } else {
                     // String is exactly eight bytes
-                    String::from_utf8_lossy(&bts[..])
+                    Some(String::from_utf8_lossy(&bts[..]))
                 }
             }
-            _ => unreachable!(),
+            _ => None,
         }
     }
 }
 
-impl<'a> Into<String> for &'a DataType {
-    fn into(self) -> String {
-        let cow: Cow<str> = self.into();
-        cow.to_string()
+impl<'a> TryFrom<&'a DataType> for Cow<'a, str> {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        d.text().ok_or(DataTypeError::WrongType {
+            expected: "Text",
+            found: d.type_name(),
+        })
     }
 }
 
-impl Into<String> for DataType {
-    fn into(self) -> String {
-        (&self).into()
+impl<'a> TryFrom<&'a DataType> for String {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        Cow::try_from(d).map(|s| s.into_owned())
     }
 }
 
-impl Into<i64> for DataType {
-    fn into(self) -> i64 {
-        match self {
-            DataType::BigInt(s) => s,
-            DataType::Int(s) => s as i64,
-            _ => unreachable!(),
+impl TryFrom<DataType> for String {
+    type Error = DataTypeError;
+
+    fn try_from(d: DataType) -> Result<Self, Self::Error> {
+        String::try_from(&d)
+    }
+}
+
+impl<'a> TryFrom<&'a DataType> for i64 {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        match *d {
+            DataType::BigInt(s) => Ok(s),
+            DataType::Int(s) => Ok(s as i64),
+            ref d => Err(DataTypeError::WrongType {
+                expected: "integer",
+                found: d.type_name(),
+            }),
         }
     }
 }
 
-impl<'a> Into<i64> for &'a DataType {
-    fn into(self) -> i64 {
-        match *self {
-            DataType::BigInt(s) => s,
-            DataType::Int(s) => s as i64,
-            _ => unreachable!(),
+impl TryFrom<DataType> for i64 {
+    type Error = DataTypeError;
+
+    fn try_from(d: DataType) -> Result<Self, Self::Error> {
+        i64::try_from(&d)
+    }
+}
+
+impl<'a> TryFrom<&'a DataType> for i32 {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        match *d {
+            DataType::Int(s) => Ok(s),
+            DataType::BigInt(s) if s >= i32::min_value() as i64 && s <= i32::max_value() as i64 => {
+                Ok(s as i32)
+            }
+            DataType::BigInt(..) => Err(DataTypeError::OutOfRange),
+            ref d => Err(DataTypeError::WrongType {
+                expected: "integer",
+                found: d.type_name(),
+            }),
         }
     }
 }
 
-impl Into<i32> for DataType {
-    fn into(self) -> i32 {
-        if let DataType::Int(s) = self {
-            s
-        } else {
-            unreachable!();
+impl TryFrom<DataType> for i32 {
+    type Error = DataTypeError;
+
+    fn try_from(d: DataType) -> Result<Self, Self::Error> {
+        i32::try_from(&d)
+    }
+}
+
+impl<'a> TryFrom<&'a DataType> for f64 {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        match *d {
+            DataType::Real(i, f) => Ok(i as f64 + (f as f64) / FLOAT_PRECISION),
+            DataType::Decimal(ref d) => {
+                Ok(d.mantissa as f64 / pow10(d.scale as u32).unwrap() as f64)
+            }
+            DataType::Int(i) => Ok(i as f64),
+            DataType::BigInt(i) => Ok(i as f64),
+            ref d => Err(DataTypeError::WrongType {
+                expected: "numeric",
+                found: d.type_name(),
+            }),
         }
     }
 }
 
-impl<'a> Into<f64> for &'a DataType {
-    fn into(self) -> f64 {
-        match self {
-            &DataType::Real(i, f) => i as f64 + (f as f64) / FLOAT_PRECISION,
-            &DataType::Int(i) => i as f64,
-            &DataType::BigInt(i) => i as f64,
-            _ => unreachable!(),
+impl TryFrom<DataType> for f64 {
+    type Error = DataTypeError;
+
+    fn try_from(d: DataType) -> Result<Self, Self::Error> {
+        f64::try_from(&d)
+    }
+}
+
+impl<'a> TryFrom<&'a DataType> for Vec<u8> {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        match *d {
+            DataType::ByteArray(ref bytes) => Ok((**bytes).clone()),
+            ref d => Err(DataTypeError::WrongType {
+                expected: "ByteArray",
+                found: d.type_name(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<DataType> for Vec<u8> {
+    type Error = DataTypeError;
+
+    fn try_from(d: DataType) -> Result<Self, Self::Error> {
+        Vec::<u8>::try_from(&d)
+    }
+}
+
+impl<'a> TryFrom<&'a DataType> for bool {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        match *d {
+            DataType::Bool(b) => Ok(b),
+            ref d => Err(DataTypeError::WrongType {
+                expected: "Bool",
+                found: d.type_name(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<DataType> for bool {
+    type Error = DataTypeError;
+
+    fn try_from(d: DataType) -> Result<Self, Self::Error> {
+        bool::try_from(&d)
+    }
+}
+
+impl<'a> TryFrom<&'a DataType> for DateTime<Utc> {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        match *d {
+            DataType::TimestampTz(ts) => Ok(ts),
+            ref d => Err(DataTypeError::WrongType {
+                expected: "TimestampTz",
+                found: d.type_name(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<DataType> for DateTime<Utc> {
+    type Error = DataTypeError;
+
+    fn try_from(d: DataType) -> Result<Self, Self::Error> {
+        DateTime::<Utc>::try_from(&d)
+    }
+}
+
+impl<'a> TryFrom<&'a DataType> for NaiveDate {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        match *d {
+            DataType::Date(d) => Ok(d),
+            ref d => Err(DataTypeError::WrongType {
+                expected: "Date",
+                found: d.type_name(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<DataType> for NaiveDate {
+    type Error = DataTypeError;
+
+    fn try_from(d: DataType) -> Result<Self, Self::Error> {
+        NaiveDate::try_from(&d)
+    }
+}
+
+impl<'a> TryFrom<&'a DataType> for Duration {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        match *d {
+            DataType::Time(t) => Ok(Duration::microseconds(t)),
+            ref d => Err(DataTypeError::WrongType {
+                expected: "Time",
+                found: d.type_name(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<DataType> for Duration {
+    type Error = DataTypeError;
+
+    fn try_from(d: DataType) -> Result<Self, Self::Error> {
+        Duration::try_from(&d)
+    }
+}
+
+impl<'a> TryFrom<&'a DataType> for [u8; 16] {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        match *d {
+            DataType::Uuid(ref u) => Ok(**u),
+            ref d => Err(DataTypeError::WrongType {
+                expected: "Uuid",
+                found: d.type_name(),
+            }),
         }
     }
 }
 
-impl From<String> for DataType {
-    fn from(s: String) -> Self {
+impl TryFrom<DataType> for [u8; 16] {
+    type Error = DataTypeError;
+
+    fn try_from(d: DataType) -> Result<Self, Self::Error> {
+        <[u8; 16]>::try_from(&d)
+    }
+}
+
+impl TryFrom<String> for DataType {
+    type Error = DataTypeError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        if s.as_bytes().contains(&0) {
+            return Err(DataTypeError::InteriorNul);
+        }
+
         let len = s.as_bytes().len();
         if len <= TINYTEXT_WIDTH {
             let mut bytes = [0; TINYTEXT_WIDTH];
@@ -335,55 +938,780 @@ impl From<String> for DataType {
                 let bts = &mut bytes[0..len];
                 bts.copy_from_slice(s.as_bytes());
             }
-            DataType::TinyText(bytes)
+            Ok(DataType::TinyText(bytes))
         } else {
-            use std::convert::TryFrom;
-            DataType::Text(ArcCStr::try_from(s).unwrap())
+            ArcCStr::try_from(s)
+                .map(DataType::Text)
+                .map_err(|_| DataTypeError::InteriorNul)
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a str> for DataType {
+    type Error = DataTypeError;
+
+    fn try_from(s: &'a str) -> Result<Self, Self::Error> {
+        DataType::try_from(s.to_owned())
+    }
+}
+
+// Parses a decimal number like -12.345 exactly, rounding past the ninth fractional digit.
+fn parse_fixed(s: &str) -> Result<DataType, DataTypeError> {
+    let invalid = DataTypeError::Parse {
+        expected: "numeric",
+    };
+    if s.contains(|c| c == 'e' || c == 'E') {
+        let f: f64 = s.parse().map_err(|_| invalid)?;
+        return DataType::try_from(f);
+    }
+
+    let (negative, digits) = if s.starts_with('-') {
+        (true, &s[1..])
+    } else {
+        (false, s.trim_start_matches('+'))
+    };
+    let mut parts = digits.splitn(2, '.');
+    let integral = parts.next().unwrap_or("");
+    let fractional = parts.next().unwrap_or("");
+    if integral.is_empty() && fractional.is_empty()
+        || !integral
+            .chars()
+            .chain(fractional.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid);
+    }
+    // anything with more digits than this doesn't fit in a Real anyway
+    if integral.trim_start_matches('0').len() > 19 {
+        return Err(DataTypeError::OutOfRange);
+    }
+
+    let mut n = integral.parse::<i128>().unwrap_or(0) * FLOAT_PRECISION as i128;
+    let mut scale = FLOAT_PRECISION as i128;
+    for (i, d) in fractional.bytes().enumerate() {
+        let d = (d - b'0') as i128;
+        if i == 9 {
+            if d >= 5 {
+                n += 1;
+            }
+            break;
         }
+        scale /= 10;
+        n += d * scale;
+    }
+    if negative {
+        n = -n;
     }
+    from_fixed(n).map_err(|_| DataTypeError::OutOfRange)
 }
 
-impl<'a> From<&'a str> for DataType {
-    fn from(s: &'a str) -> Self {
-        DataType::from(s.to_owned())
+// Parses bytes written the way `Display` writes them, as x'0a1b', or else takes the raw bytes.
+fn parse_bytes(s: &str) -> Result<DataType, DataTypeError> {
+    if !(s.starts_with("x'") && s.ends_with('\'') && s.len() >= 3) {
+        return Ok(s.as_bytes().to_vec().into());
+    }
+
+    let invalid = DataTypeError::Parse {
+        expected: "ByteArray",
+    };
+    let hex = s[2..s.len() - 1].as_bytes();
+    if hex.len() % 2 != 0 || !hex.iter().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid);
     }
+    let digit = |b: u8| (b as char).to_digit(16).unwrap() as u8;
+    Ok(hex
+        .chunks(2)
+        .map(|pair| digit(pair[0]) << 4 | digit(pair[1]))
+        .collect::<Vec<u8>>()
+        .into())
 }
 
-// Performs an arithmetic operation on two numeric DataTypes,
-// returning a new DataType as the result.
-macro_rules! arithmetic_operation (
-    ($op:tt, $first:ident, $second:ident) => (
-        match ($first, $second) {
-            (&DataType::None, _) | (_, &DataType::None) => DataType::None,
-            (&DataType::Int(a), &DataType::Int(b)) => (a $op b).into(),
-            (&DataType::BigInt(a), &DataType::BigInt(b)) => (a $op b).into(),
-            (&DataType::Int(a), &DataType::BigInt(b)) => ((a as i64) $op b).into(),
-            (&DataType::BigInt(a), &DataType::Int(b)) => (a $op (b as i64)).into(),
+// Parses a timestamp as RFC 3339 or ISO-8601, or in the `%c` format `Display` used to write.
+// Timestamps with an offset are converted to UTC, and ones without are taken to be in UTC.
+fn parse_timestamp(s: &str) -> Result<DateTime<Utc>, DataTypeError> {
+    let formats = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%c"];
+    DateTime::parse_from_rfc3339(s)
+        .map(|ts| ts.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            formats
+                .iter()
+                .filter_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+                .next()
+                .map(|ts| Utc.from_utc_datetime(&ts))
+        })
+        .ok_or(DataTypeError::Parse {
+            expected: "Timestamp",
+        })
+}
+
+impl DataType {
+    /// Parse a value of the given SQL type from a string, as written by `Display` or
+    /// `to_string`.
+    ///
+    /// `NULL` and `*` parse as `None` for every type. Text may be surrounded by double quotes,
+    /// which are removed, so `"*"` is the text `*`. Timestamps can be written in RFC 3339,
+    /// ISO-8601 or the `%c` format, and binary values as `x'0a1b'`. `TIMESTAMP` values are
+    /// `TimestampTz`s, and `DATETIME` values are `Timestamp`s in UTC.
+    pub fn parse(s: &str, ty: &SqlType) -> Result<DataType, DataTypeError> {
+        if s == "*" || s.eq_ignore_ascii_case("null") {
+            return Ok(DataType::None);
+        }
 
-            (first @ &DataType::Int(..), second @ &DataType::Real(..)) |
-            (first @ &DataType::Real(..), second @ &DataType::Int(..)) |
-            (first @ &DataType::Real(..), second @ &DataType::Real(..)) => {
-                let a: f64 = first.into();
-                let b: f64 = second.into();
-                (a $op b).into()
+        let integer = |expected| {
+            s.parse::<i64>().map_err(|_| {
+                // well-formed integers only fail to parse if they're too large
+                let digits = s.trim_start_matches(|c| c == '-' || c == '+');
+                if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+                    DataTypeError::OutOfRange
+                } else {
+                    DataTypeError::Parse { expected }
+                }
+            })
+        };
+        let unsigned = |n: i64| {
+            if n < 0 {
+                Err(DataTypeError::OutOfRange)
+            } else {
+                Ok(n)
             }
-            (first, second) => panic!(
-                format!(
-                    "can't {} a {:?} and {:?}",
-                    stringify!($op),
-                    first,
-                    second,
-                )
-            ),
+        };
+        let text = || {
+            if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+                DataType::try_from(&s[1..s.len() - 1])
+            } else {
+                DataType::try_from(s)
+            }
+        };
+
+        match *ty {
+            SqlType::Bool => match s.to_ascii_lowercase().as_str() {
+                "true" | "1" => Ok(DataType::Bool(true)),
+                "false" | "0" => Ok(DataType::Bool(false)),
+                _ => Err(DataTypeError::Parse { expected: "Bool" }),
+            },
+            SqlType::Int(..) | SqlType::Tinyint(..) => {
+                let n = integer("Int")?;
+                if n < i32::min_value() as i64 || n > i32::max_value() as i64 {
+                    return Err(DataTypeError::OutOfRange);
+                }
+                Ok(DataType::Int(n as i32))
+            }
+            SqlType::UnsignedInt(..) | SqlType::UnsignedTinyint(..) => {
+                let n = unsigned(integer("Int")?)?;
+                if n > i32::max_value() as i64 {
+                    Ok(DataType::BigInt(n))
+                } else {
+                    Ok(DataType::Int(n as i32))
+                }
+            }
+            SqlType::Bigint(..) => integer("BigInt").map(DataType::BigInt),
+            SqlType::UnsignedBigint(..) => unsigned(integer("BigInt")?).map(DataType::BigInt),
+            SqlType::Double | SqlType::Float | SqlType::Real => parse_fixed(s),
+            SqlType::Decimal(precision, scale) => s
+                .parse::<Decimal>()?
+                .rescale(precision, scale, Rounding::HalfUp)
+                .map(DataType::from),
+            SqlType::Char(..)
+            | SqlType::Varchar(..)
+            | SqlType::Tinytext
+            | SqlType::Mediumtext
+            | SqlType::Longtext
+            | SqlType::Text => text(),
+            SqlType::Enum(ref variants) => {
+                let value = text()?;
+                let allowed = variants.iter().any(|v| match *v {
+                    Literal::String(ref v) => DataType::try_from(v.as_str()).as_ref() == Ok(&value),
+                    _ => false,
+                });
+                if allowed {
+                    Ok(value)
+                } else {
+                    Err(DataTypeError::Parse { expected: "Enum" })
+                }
+            }
+            SqlType::Blob
+            | SqlType::Longblob
+            | SqlType::Mediumblob
+            | SqlType::Tinyblob
+            | SqlType::Binary(..)
+            | SqlType::Varbinary(..) => parse_bytes(s),
+            SqlType::Date => NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map(DataType::Date)
+                .map_err(|_| DataTypeError::Parse { expected: "Date" }),
+            SqlType::DateTime(..) => {
+                parse_timestamp(s).map(|ts| DataType::Timestamp(ts.naive_utc()))
+            }
+            SqlType::Timestamp => parse_timestamp(s).map(DataType::TimestampTz),
         }
-    );
-);
+    }
+}
 
+/// The most digits a `Decimal` can have.
+pub const MAX_PRECISION: u8 = 38;
+// How many more decimal places the result of a division has than the dividend.
+const DIVISION_SCALE: u8 = 4;
+
+// 10^n, or `None` if it doesn't fit an i128, which is the case for n > MAX_PRECISION.
+fn pow10(n: u32) -> Option<i128> {
+    10i128.checked_pow(n)
+}
+
+// The number of decimal digits in n, not counting the sign.
+fn num_digits(n: i128) -> u8 {
+    let mut n = n;
+    let mut digits = 0;
+    while n != 0 {
+        n /= 10;
+        digits += 1;
+    }
+    digits
+}
+
+/// How to round a number that has more decimal places than it can keep.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Rounding {
+    /// Round to the nearest number, and halves away from zero.
+    HalfUp,
+    /// Round to the nearest number, and halves to the even neighbor.
+    HalfEven,
+    /// Drop the extra digits.
+    TowardZero,
+    /// Round any extra digits away from zero.
+    AwayFromZero,
+}
+
+impl Rounding {
+    // Divide, rounding the quotient this way.
+    fn div(&self, n: i128, d: i128) -> i128 {
+        let q = n / d;
+        let r = (n % d).abs();
+        if r == 0 {
+            return q;
+        }
+        let away = match *self {
+            Rounding::HalfUp => r >= d.abs() - r,
+            Rounding::HalfEven => r > d.abs() - r || (r == d.abs() - r && q % 2 != 0),
+            Rounding::TowardZero => false,
+            Rounding::AwayFromZero => true,
+        };
+        if !away {
+            q
+        } else if (n < 0) == (d < 0) {
+            q + 1
+        } else {
+            q - 1
+        }
+    }
+}
+
+/// An exact decimal number with up to `MAX_PRECISION` digits, `scale` of which are after the
+/// decimal point.
+///
+/// Decimals are equal if they have the same value, so 1.5 and 1.50 are the same key.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawDecimal")]
+pub struct Decimal {
+    mantissa: i128,
+    precision: u8,
+    scale: u8,
+}
+
+// A decimal as it is serialized, which is checked by `Decimal::new` when it is deserialized.
+#[derive(Deserialize)]
+struct RawDecimal {
+    mantissa: i128,
+    precision: u8,
+    scale: u8,
+}
+
+impl TryFrom<RawDecimal> for Decimal {
+    type Error = DataTypeError;
+
+    fn try_from(d: RawDecimal) -> Result<Self, Self::Error> {
+        Decimal::new(d.mantissa, d.precision, d.scale)
+    }
+}
+
+impl Decimal {
+    /// The decimal `mantissa / 10^scale`, which must have at most `precision` digits.
+    pub fn new(mantissa: i128, precision: u8, scale: u8) -> Result<Decimal, DataTypeError> {
+        if precision == 0
+            || precision > MAX_PRECISION
+            || scale > precision
+            || num_digits(mantissa) > precision
+        {
+            return Err(DataTypeError::OutOfRange);
+        }
+        Ok(Decimal {
+            mantissa: mantissa,
+            precision: precision,
+            scale: scale,
+        })
+    }
+
+    // A decimal with the smallest precision that holds the mantissa, or an overflow if nothing
+    // does. If there are too many digits, the least significant fractional ones are rounded off.
+    fn fit(
+        mantissa: i128,
+        precision: u8,
+        scale: u8,
+        rounding: Rounding,
+    ) -> Result<Decimal, DataTypeError> {
+        let (mut mantissa, mut scale) = (mantissa, scale);
+        let digits = num_digits(mantissa);
+        let excess = cmp::max(
+            digits.saturating_sub(MAX_PRECISION),
+            scale.saturating_sub(MAX_PRECISION),
+        );
+        if excess > scale {
+            return Err(DataTypeError::Overflow);
+        } else if excess > 0 {
+            let d = pow10(excess as u32).ok_or(DataTypeError::Overflow)?;
+            mantissa = rounding.div(mantissa, d);
+            scale -= excess;
+        }
+        let precision = cmp::max(
+            cmp::max(precision, num_digits(mantissa)),
+            cmp::max(scale, 1),
+        );
+        Decimal::new(mantissa, cmp::min(precision, MAX_PRECISION), scale)
+            .map_err(|_| DataTypeError::Overflow)
+    }
+
+    pub fn mantissa(&self) -> i128 {
+        self.mantissa
+    }
+
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    /// This value with the given precision and scale, rounding off any fractional digits that
+    /// don't fit. Fails if the integer part doesn't fit.
+    pub fn rescale(
+        &self,
+        precision: u8,
+        scale: u8,
+        rounding: Rounding,
+    ) -> Result<Decimal, DataTypeError> {
+        if precision == 0 || precision > MAX_PRECISION || scale > precision {
+            return Err(DataTypeError::OutOfRange);
+        }
+        let mantissa = if scale >= self.scale {
+            pow10((scale - self.scale) as u32)
+                .and_then(|m| self.mantissa.checked_mul(m))
+                .ok_or(DataTypeError::OutOfRange)?
+        } else {
+            let d = pow10((self.scale - scale) as u32).ok_or(DataTypeError::OutOfRange)?;
+            rounding.div(self.mantissa, d)
+        };
+        Decimal::new(mantissa, precision, scale)
+    }
+
+    // The mantissas of both values with the larger of their scales.
+    fn align(&self, other: &Decimal) -> Result<(i128, i128, u8), DataTypeError> {
+        let scale = cmp::max(self.scale, other.scale);
+        let widen = |d: &Decimal| {
+            pow10((scale - d.scale) as u32)
+                .and_then(|m| d.mantissa.checked_mul(m))
+                .ok_or(DataTypeError::Overflow)
+        };
+        Ok((widen(self)?, widen(other)?, scale))
+    }
+
+    /// Add two decimals exactly. The result has the larger of their scales.
+    pub fn checked_add(&self, other: &Decimal) -> Result<Decimal, DataTypeError> {
+        let (a, b, scale) = self.align(other)?;
+        let integral = cmp::max(self.precision - self.scale, other.precision - other.scale);
+        let sum = a.checked_add(b).ok_or(DataTypeError::Overflow)?;
+        let precision = integral
+            .checked_add(scale)
+            .and_then(|p| p.checked_add(1))
+            .ok_or(DataTypeError::Overflow)?;
+        Decimal::fit(sum, precision, scale, Rounding::HalfUp)
+    }
+
+    /// Subtract `other` from this decimal exactly, like `checked_add`.
+    pub fn checked_sub(&self, other: &Decimal) -> Result<Decimal, DataTypeError> {
+        let negated = Decimal {
+            mantissa: -other.mantissa,
+            ..other.clone()
+        };
+        self.checked_add(&negated)
+    }
+
+    /// Multiply two decimals exactly. The result's scale is the sum of their scales, unless
+    /// that would need more than `MAX_PRECISION` digits, in which case the product is rounded.
+    pub fn checked_mul(&self, other: &Decimal) -> Result<Decimal, DataTypeError> {
+        let product = self
+            .mantissa
+            .checked_mul(other.mantissa)
+            .ok_or(DataTypeError::Overflow)?;
+        let precision = self.precision.checked_add(other.precision);
+        let scale = self.scale.checked_add(other.scale);
+        match (precision, scale) {
+            (Some(precision), Some(scale)) => {
+                Decimal::fit(product, precision, scale, Rounding::HalfUp)
+            }
+            _ => Err(DataTypeError::Overflow),
+        }
+    }
+
+    /// Divide this decimal by `other`, rounding the quotient to the given scale.
+    ///
+    /// Fails with `Overflow` if the quotient, or the dividend widened to the scale of the
+    /// quotient, needs more than `MAX_PRECISION` digits.
+    pub fn checked_div(
+        &self,
+        other: &Decimal,
+        scale: u8,
+        rounding: Rounding,
+    ) -> Result<Decimal, DataTypeError> {
+        if other.mantissa == 0 {
+            return Err(DataTypeError::DivisionByZero);
+        }
+        // trailing zeros of the divisor only make the dividend need widening further
+        let (divisor, divisor_scale) = other.normalized();
+        // the quotient of the mantissas is off by 10^(divisor_scale - self.scale)
+        let shift = scale as i32 + divisor_scale as i32 - self.scale as i32;
+        let quotient = if shift >= 0 {
+            let n = pow10(shift as u32)
+                .and_then(|m| self.mantissa.checked_mul(m))
+                .ok_or(DataTypeError::Overflow)?;
+            rounding.div(n, divisor)
+        } else {
+            let d = pow10(-shift as u32)
+                .and_then(|m| divisor.checked_mul(m))
+                .ok_or(DataTypeError::Overflow)?;
+            rounding.div(self.mantissa, d)
+        };
+        let precision = (self.precision - self.scale)
+            .checked_add(divisor_scale)
+            .and_then(|p| p.checked_add(scale))
+            .ok_or(DataTypeError::Overflow)?;
+        Decimal::fit(quotient, precision, scale, rounding)
+    }
+
+    // The mantissa and scale without trailing fractional zeros, which is the same for all
+    // decimals with the same value.
+    fn normalized(&self) -> (i128, u8) {
+        let (mut mantissa, mut scale) = (self.mantissa, self.scale);
+        while scale > 0 && mantissa % 10 == 0 {
+            mantissa /= 10;
+            scale -= 1;
+        }
+        (mantissa, scale)
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Decimal) -> bool {
+        self.normalized() == other.normalized()
+    }
+}
+
+impl Eq for Decimal {}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Decimal) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Decimal) -> Ordering {
+        // compare the integer parts, then the fractional parts widened to the same scale, which
+        // can't overflow since scales are at most MAX_PRECISION
+        let parts = |d: &Decimal| {
+            let scale = pow10(d.scale as u32).unwrap();
+            let fraction = d.mantissa % scale * pow10((MAX_PRECISION - d.scale) as u32).unwrap();
+            (d.mantissa / scale, fraction)
+        };
+        parts(self).cmp(&parts(other))
+    }
+}
+
+impl Hash for Decimal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.normalized().hash(state)
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scale = pow10(self.scale as u32).unwrap();
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        let integral = (self.mantissa / scale).abs();
+        if self.scale == 0 {
+            write!(f, "{}{}", sign, integral)
+        } else {
+            let fraction = (self.mantissa % scale).abs();
+            let width = self.scale as usize;
+            write!(
+                f,
+                "{}{}.{:0width$}",
+                sign,
+                integral,
+                fraction,
+                width = width
+            )
+        }
+    }
+}
+
+impl fmt::Debug for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Decimal({}, {}, {})", self, self.precision, self.scale)
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = DataTypeError;
+
+    /// Parses a number like -12.50 exactly, keeping its scale.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = DataTypeError::Parse {
+            expected: "Decimal",
+        };
+        let (negative, digits) = if s.starts_with('-') {
+            (true, &s[1..])
+        } else {
+            (false, s.trim_start_matches('+'))
+        };
+        let mut parts = digits.splitn(2, '.');
+        let integral = parts.next().unwrap_or("");
+        let fractional = parts.next().unwrap_or("");
+        if integral.is_empty() && fractional.is_empty()
+            || !integral
+                .chars()
+                .chain(fractional.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid);
+        }
+        if integral.trim_start_matches('0').len() + fractional.len() > MAX_PRECISION as usize {
+            return Err(DataTypeError::OutOfRange);
+        }
+
+        let mantissa = integral
+            .chars()
+            .chain(fractional.chars())
+            .fold(0i128, |m, c| m * 10 + c.to_digit(10).unwrap() as i128);
+        let scale = fractional.len() as u8;
+        let precision = cmp::max(cmp::max(num_digits(mantissa), scale), 1);
+        Decimal::new(
+            if negative { -mantissa } else { mantissa },
+            precision,
+            scale,
+        )
+    }
+}
+
+impl<'a> TryFrom<&'a Real> for Decimal {
+    type Error = DataTypeError;
+
+    /// Converts a nom_sql fixed point number with no fractional part.
+    ///
+    /// nom_sql drops the leading zeros of the fractional part, so 1.05 and 1.5 are the same
+    /// `Real`, and any other number fails with `OutOfRange` rather than risk converting to the
+    /// wrong value. Parse the literal's text with `str::parse` instead.
+    fn try_from(r: &'a Real) -> Result<Self, Self::Error> {
+        if r.fractional != 0 {
+            return Err(DataTypeError::OutOfRange);
+        }
+        Decimal::fit(r.integral as i128, 1, 0, Rounding::HalfUp)
+    }
+}
+
+// A number as a decimal, if it is one.
+fn to_decimal(d: &DataType) -> Option<Decimal> {
+    match *d {
+        DataType::Decimal(ref d) => Some((**d).clone()),
+        DataType::Real(..) | DataType::Int(..) | DataType::BigInt(..) => {
+            let n = Decimal::fit(to_fixed(d)?, 1, 9, Rounding::HalfUp).ok()?;
+            let (mantissa, scale) = n.normalized();
+            Decimal::fit(mantissa, 1, scale, Rounding::HalfUp).ok()
+        }
+        _ => None,
+    }
+}
+
+impl From<Decimal> for DataType {
+    fn from(d: Decimal) -> Self {
+        DataType::Decimal(Arc::new(d))
+    }
+}
+
+impl<'a> TryFrom<&'a DataType> for Decimal {
+    type Error = DataTypeError;
+
+    fn try_from(d: &'a DataType) -> Result<Self, Self::Error> {
+        to_decimal(d).ok_or(DataTypeError::WrongType {
+            expected: "numeric",
+            found: d.type_name(),
+        })
+    }
+}
+
+impl TryFrom<DataType> for Decimal {
+    type Error = DataTypeError;
+
+    fn try_from(d: DataType) -> Result<Self, Self::Error> {
+        Decimal::try_from(&d)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Arithmetic {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+// A number as an exact count of billionths, which is how `Real`s are stored.
+fn to_fixed(d: &DataType) -> Option<i128> {
+    let precision = FLOAT_PRECISION as i128;
+    match *d {
+        DataType::Int(n) => Some(n as i128 * precision),
+        DataType::BigInt(n) => Some(n as i128 * precision),
+        DataType::Real(i, f) => Some(i as i128 * precision + f as i128),
+        _ => None,
+    }
+}
+
+fn from_fixed(n: i128) -> Result<DataType, DataTypeError> {
+    let precision = FLOAT_PRECISION as i128;
+    let i = n / precision;
+    if i < i64::min_value() as i128 || i > i64::max_value() as i128 {
+        return Err(DataTypeError::Overflow);
+    }
+    // the fractional part has the same sign as the integer part
+    Ok(DataType::Real(i as i64, (n % precision) as i32))
+}
+
+// Divide, rounding halves away from zero.
+fn div_round(n: i128, d: i128) -> i128 {
+    let q = n / d;
+    let r = n % d;
+    if 2 * r.abs() >= d.abs() {
+        if (n < 0) == (d < 0) {
+            q + 1
+        } else {
+            q - 1
+        }
+    } else {
+        q
+    }
+}
+
+impl DataType {
+    // Performs an arithmetic operation on two numeric DataTypes, returning a new DataType as the
+    // result.
+    fn arithmetic(&self, other: &DataType, op: Arithmetic) -> Result<DataType, DataTypeError> {
+        let integer = |a: i64, b: i64| match op {
+            Arithmetic::Add => a.checked_add(b).ok_or(DataTypeError::Overflow),
+            Arithmetic::Sub => a.checked_sub(b).ok_or(DataTypeError::Overflow),
+            Arithmetic::Mul => a.checked_mul(b).ok_or(DataTypeError::Overflow),
+            Arithmetic::Div if b == 0 => Err(DataTypeError::DivisionByZero),
+            Arithmetic::Div => a.checked_div(b).ok_or(DataTypeError::Overflow),
+        };
+
+        match (self, other) {
+            (&DataType::None, _) | (_, &DataType::None) => Ok(DataType::None),
+            (&DataType::Decimal(..), _) | (_, &DataType::Decimal(..)) => {
+                let wrong_type = |d: &DataType| DataTypeError::WrongType {
+                    expected: "numeric",
+                    found: d.type_name(),
+                };
+                let a = to_decimal(self).ok_or_else(|| wrong_type(self))?;
+                let b = to_decimal(other).ok_or_else(|| wrong_type(other))?;
+                let n = match op {
+                    Arithmetic::Add => a.checked_add(&b),
+                    Arithmetic::Sub => a.checked_sub(&b),
+                    Arithmetic::Mul => a.checked_mul(&b),
+                    Arithmetic::Div => {
+                        let scale = cmp::min(a.scale + DIVISION_SCALE, MAX_PRECISION);
+                        a.checked_div(&b, scale, Rounding::HalfUp)
+                    }
+                };
+                n.map(DataType::from)
+            }
+            (&DataType::Int(a), &DataType::Int(b)) => {
+                // promote to a BigInt if the result doesn't fit in an Int
+                let n = integer(a as i64, b as i64)?;
+                if n >= i32::min_value() as i64 && n <= i32::max_value() as i64 {
+                    Ok(DataType::Int(n as i32))
+                } else {
+                    Ok(DataType::BigInt(n))
+                }
+            }
+            (&DataType::BigInt(a), &DataType::BigInt(b)) => integer(a, b).map(DataType::BigInt),
+            (&DataType::Int(a), &DataType::BigInt(b)) => integer(a as i64, b).map(DataType::BigInt),
+            (&DataType::BigInt(a), &DataType::Int(b)) => integer(a, b as i64).map(DataType::BigInt),
+            (first, second) => {
+                // at least one of the two is a Real, so compute exactly in billionths
+                let wrong_type = |d: &DataType| DataTypeError::WrongType {
+                    expected: "numeric",
+                    found: d.type_name(),
+                };
+                let a = to_fixed(first).ok_or_else(|| wrong_type(first))?;
+                let b = to_fixed(second).ok_or_else(|| wrong_type(second))?;
+                let precision = FLOAT_PRECISION as i128;
+                let n = match op {
+                    Arithmetic::Add => a + b,
+                    Arithmetic::Sub => a - b,
+                    Arithmetic::Mul => {
+                        div_round(a.checked_mul(b).ok_or(DataTypeError::Overflow)?, precision)
+                    }
+                    Arithmetic::Div if b == 0 => return Err(DataTypeError::DivisionByZero),
+                    Arithmetic::Div => div_round(a * precision, b),
+                };
+                from_fixed(n)
+            }
+        }
+    }
+
+    /// Add two numeric values.
+    ///
+    /// `Int`s are promoted to `BigInt`s when the result doesn't fit in an `Int`, and `Real`s
+    /// are added exactly. If either value is a `Decimal`, so is the result. Adding `None` to
+    /// anything gives `None`.
+    pub fn checked_add(&self, other: &DataType) -> Result<DataType, DataTypeError> {
+        self.arithmetic(other, Arithmetic::Add)
+    }
+
+    /// Subtract `other` from this value, like `checked_add`.
+    pub fn checked_sub(&self, other: &DataType) -> Result<DataType, DataTypeError> {
+        self.arithmetic(other, Arithmetic::Sub)
+    }
+
+    /// Multiply two numeric values, like `checked_add`. Products of `Real`s are rounded to the
+    /// nearest billionth.
+    pub fn checked_mul(&self, other: &DataType) -> Result<DataType, DataTypeError> {
+        self.arithmetic(other, Arithmetic::Mul)
+    }
+
+    /// Divide this value by `other`, like `checked_add`. Division of integers truncates, and
+    /// division involving `Real`s is rounded to the nearest billionth. Division involving
+    /// `Decimal`s gives four more decimal places than the dividend has, rounding halves up.
+    pub fn checked_div(&self, other: &DataType) -> Result<DataType, DataTypeError> {
+        self.arithmetic(other, Arithmetic::Div)
+    }
+}
+
+// The operators panic where the checked methods would return an error.
 impl<'a, 'b> Add<&'b DataType> for &'a DataType {
     type Output = DataType;
 
     fn add(self, other: &'b DataType) -> DataType {
-        arithmetic_operation!(+, self, other)
+        self.checked_add(other)
+            .unwrap_or_else(|e| panic!("can't + a {:?} and {:?}: {}", self, other, e))
     }
 }
 
@@ -391,7 +1719,8 @@ impl<'a, 'b> Sub<&'b DataType> for &'a DataType {
     type Output = DataType;
 
     fn sub(self, other: &'b DataType) -> DataType {
-        arithmetic_operation!(-, self, other)
+        self.checked_sub(other)
+            .unwrap_or_else(|e| panic!("can't - a {:?} and {:?}: {}", self, other, e))
     }
 }
 
@@ -399,7 +1728,8 @@ impl<'a, 'b> Mul<&'b DataType> for &'a DataType {
     type Output = DataType;
 
     fn mul(self, other: &'b DataType) -> DataType {
-        arithmetic_operation!(*, self, other)
+        self.checked_mul(other)
+            .unwrap_or_else(|e| panic!("can't * a {:?} and {:?}: {}", self, other, e))
     }
 }
 
@@ -407,7 +1737,8 @@ impl<'a, 'b> Div<&'b DataType> for &'a DataType {
     type Output = DataType;
 
     fn div(self, other: &'b DataType) -> DataType {
-        arithmetic_operation!(/, self, other)
+        self.checked_div(other)
+            .unwrap_or_else(|e| panic!("can't / a {:?} and {:?}: {}", self, other, e))
     }
 }
 
@@ -416,17 +1747,24 @@ impl fmt::Debug for DataType {
         match *self {
             DataType::None => write!(f, "None"),
             DataType::Text(..) => {
-                let text: Cow<str> = self.into();
+                let text = self.text().unwrap_or_default();
                 write!(f, "Text({:?})", text)
             }
             DataType::TinyText(..) => {
-                let text: Cow<str> = self.into();
+                let text = self.text().unwrap_or_default();
                 write!(f, "TinyText({:?})", text)
             }
             DataType::Timestamp(ts) => write!(f, "Timestamp({:?})", ts),
+            DataType::TimestampTz(ts) => write!(f, "TimestampTz({:?})", ts),
             DataType::Real(..) => write!(f, "Real({})", self),
             DataType::Int(n) => write!(f, "Int({})", n),
             DataType::BigInt(n) => write!(f, "BigInt({})", n),
+            DataType::ByteArray(ref bytes) => write!(f, "ByteArray({:?})", bytes),
+            DataType::Bool(b) => write!(f, "Bool({})", b),
+            DataType::Date(d) => write!(f, "Date({:?})", d),
+            DataType::Time(..) => write!(f, "Time({})", self),
+            DataType::Uuid(..) => write!(f, "Uuid({})", self),
+            DataType::Decimal(..) => write!(f, "Decimal({})", self),
         }
     }
 }
@@ -436,7 +1774,7 @@ impl fmt::Display for DataType {
         match *self {
             DataType::None => write!(f, "*"),
             DataType::Text(..) | DataType::TinyText(..) => {
-                let text: Cow<str> = self.into();
+                let text = self.text().unwrap_or_default();
                 write!(f, "\"{}\"", text)
             }
             DataType::Int(n) => write!(f, "{}", n),
@@ -449,17 +1787,248 @@ impl fmt::Display for DataType {
                     write!(f, "{}", format!("{}.{:09}", i, frac.abs()))
                 }
             }
-            DataType::Timestamp(ts) => write!(f, "{}", format!("{}", ts.format("%c"))),
+            DataType::Timestamp(ts) => write!(f, "{}", ts.format("%Y-%m-%dT%H:%M:%S%.f")),
+            DataType::TimestampTz(ts) => write!(f, "{}", ts.to_rfc3339()),
+            DataType::ByteArray(ref bytes) => fmt_bytes(bytes, f),
+            DataType::Bool(b) => write!(f, "{}", b),
+            DataType::Date(d) => write!(f, "{}", d.format("%Y-%m-%d")),
+            DataType::Time(t) => fmt_time(t, f),
+            DataType::Uuid(ref bytes) => fmt_uuid(bytes, f),
+            DataType::Decimal(ref d) => write!(f, "{}", d),
+        }
+    }
+}
+
+/// How text values are compared, hashed and ordered.
+///
+/// `DataType`'s own comparisons, hashing and ordering are always binary, so that existing maps
+/// keep their keys. Collations apply through `DataType::cmp_collated`, and through `Collated`,
+/// which is the key type to use for a map with case-insensitive keys.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum Collation {
+    /// Text is compared byte by byte.
+    Binary,
+    /// Text is compared ignoring the case of ASCII letters.
+    AsciiCaseInsensitive,
+    /// Text is compared after Unicode (NFC) normalization, so that the different ways of
+    /// writing an accented character are equal.
+    UnicodeNormalized,
+}
+
+impl Default for Collation {
+    fn default() -> Self {
+        Collation::Binary
+    }
+}
+
+impl Collation {
+    // Rewrite the text so that strings this collation considers equal are identical.
+    fn fold<'a>(&self, s: Cow<'a, str>) -> Cow<'a, str> {
+        match *self {
+            Collation::Binary => s,
+            Collation::AsciiCaseInsensitive if !s.bytes().any(|b| b.is_ascii_uppercase()) => s,
+            Collation::AsciiCaseInsensitive => Cow::Owned(s.to_ascii_lowercase()),
+            Collation::UnicodeNormalized if is_nfc(&s) => s,
+            Collation::UnicodeNormalized => Cow::Owned(s.nfc().collect()),
         }
     }
 }
 
+impl DataType {
+    /// The value that stands in for this one under the given collation. Text is rewritten so
+    /// that values the collation considers equal are identical, and other values are returned
+    /// as they are.
+    pub fn collate(&self, collation: Collation) -> DataType {
+        match *self {
+            DataType::Text(..) | DataType::TinyText(..) if collation != Collation::Binary => {
+                match collation.fold(self.text().unwrap_or_default()) {
+                    Cow::Borrowed(..) => self.clone(),
+                    // folding doesn't introduce NUL bytes
+                    Cow::Owned(s) => DataType::try_from(s).unwrap(),
+                }
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// Compare two values, with any text compared under the given collation.
+    pub fn cmp_collated(&self, other: &DataType, collation: Collation) -> Ordering {
+        self.collate(collation).cmp(&other.collate(collation))
+    }
+}
+
+/// A value that is compared, hashed and ordered under a collation, so that it can be used as a
+/// case-insensitive map key. The original value is kept, and can be read back unchanged.
+///
+/// Only the value and collation are serialized, and the key is recomputed when it is
+/// deserialized.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "RawCollated")]
+pub struct Collated {
+    value: DataType,
+    collation: Collation,
+    #[serde(skip_serializing)]
+    key: DataType,
+}
+
+// A collated value as it is serialized.
+#[derive(Deserialize)]
+struct RawCollated {
+    value: DataType,
+    collation: Collation,
+}
+
+impl From<RawCollated> for Collated {
+    fn from(c: RawCollated) -> Self {
+        Collated::new(c.value, c.collation)
+    }
+}
+
+impl Collated {
+    pub fn new(value: DataType, collation: Collation) -> Self {
+        Collated {
+            key: value.collate(collation),
+            value: value,
+            collation: collation,
+        }
+    }
+
+    /// The value as it was given.
+    pub fn value(&self) -> &DataType {
+        &self.value
+    }
+
+    pub fn collation(&self) -> Collation {
+        self.collation
+    }
+
+    pub fn into_inner(self) -> DataType {
+        self.value
+    }
+}
+
+impl PartialEq for Collated {
+    fn eq(&self, other: &Collated) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Collated {}
+
+impl PartialOrd for Collated {
+    fn partial_cmp(&self, other: &Collated) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Collated {
+    fn cmp(&self, other: &Collated) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+impl Hash for Collated {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state)
+    }
+}
+
+impl fmt::Display for Collated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Operation {
     Add,
     Sub,
 }
 
+/// An aggregation that can be computed over a column of a set of rows.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Agg {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+impl Agg {
+    /// Compute this aggregation over the `column`th field of each of the given rows.
+    ///
+    /// As in SQL, `DataType::None` fields (and rows that are too short to have the column) are
+    /// ignored. `Count` of no values is `0`; every other aggregation of no values is `None`.
+    /// `Sum` and `Avg` fail if a field isn't a number, or if the sum overflows.
+    pub fn apply(&self, rows: &[Vec<DataType>], column: usize) -> Result<DataType, DataTypeError> {
+        let mut values = rows
+            .iter()
+            .filter_map(|r| r.get(column))
+            .filter(|v| **v != DataType::None);
+
+        match *self {
+            Agg::Count => Ok(values.count().into()),
+            Agg::Min => Ok(values.min().cloned().unwrap_or(DataType::None)),
+            Agg::Max => Ok(values.max().cloned().unwrap_or(DataType::None)),
+            Agg::Sum | Agg::Avg => {
+                let first = match values.next() {
+                    Some(v) if to_decimal(v).is_some() => v.clone(),
+                    Some(v) => {
+                        return Err(DataTypeError::WrongType {
+                            expected: "numeric",
+                            found: v.type_name(),
+                        })
+                    }
+                    None => return Ok(DataType::None),
+                };
+                let mut count = 1;
+                let mut sum = first;
+                for v in values {
+                    count += 1;
+                    sum = sum.checked_add(v)?;
+                }
+                if *self == Agg::Sum {
+                    Ok(sum)
+                } else {
+                    // divide by a Real so that averages of integers aren't truncated
+                    sum.checked_div(&DataType::Real(count as i64, 0))
+                }
+            }
+        }
+    }
+}
+
+/// A row-level security rule that decides which users can see a row.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Policy {
+    /// Users can see rows whose `column`th field is their user id. Through a `Handle`, that is
+    /// the id the application uses for the user if one was set (see `Handle::set_external_id`).
+    ColumnIsUser(usize),
+    /// Every user can see rows whose `column`th field equals the given value, as SQL compares
+    /// them, so a `None` value matches no rows.
+    ColumnEquals(usize, DataType),
+    /// Users can see rows that any of the given policies allows them to.
+    Any(Vec<Policy>),
+    /// Users can see rows that all of the given policies allow them to.
+    All(Vec<Policy>),
+}
+
+impl Policy {
+    /// Whether the given user can see the given row. Rows that are too short to have a column
+    /// that the policy looks at are not visible.
+    pub fn allows(&self, row: &[DataType], uid: usize) -> bool {
+        match *self {
+            Policy::ColumnIsUser(column) => row.get(column) == Some(&DataType::from(uid)),
+            Policy::ColumnEquals(column, ref value) => {
+                row.get(column).and_then(|v| v.sql_eq(value)) == Some(true)
+            }
+            Policy::Any(ref policies) => policies.iter().any(|p| p.allows(row, uid)),
+            Policy::All(ref policies) => policies.iter().all(|p| p.allows(row, uid)),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Modification {
     Set(DataType),
@@ -656,6 +2225,9 @@ impl SizeOf for DataType {
 
         let inner = match *self {
             DataType::Text(ref t) => size_of_val(t) as usize + t.to_bytes().len() as usize,
+            DataType::ByteArray(ref b) => size_of_val(&**b) + b.len(),
+            DataType::Uuid(ref u) => size_of_val(&**u),
+            DataType::Decimal(ref d) => size_of_val(&**d),
             _ => 0usize,
         };
 
@@ -670,6 +2242,24 @@ impl SizeOf for DataType {
     }
 }
 
+impl SizeOf for Collated {
+    fn deep_size_of(&self) -> usize {
+        let inner = self.value.deep_size_of() - self.value.size_of();
+        if self.collation == Collation::Binary {
+            // the key shares the value's data
+            self.size_of() + inner
+        } else {
+            self.size_of() + inner + self.key.deep_size_of() - self.key.size_of()
+        }
+    }
+
+    fn size_of(&self) -> usize {
+        use std::mem::size_of;
+
+        size_of::<Collated>()
+    }
+}
+
 impl SizeOf for Vec<DataType> {
     fn deep_size_of(&self) -> usize {
         use std::mem::size_of_val;
@@ -1,11 +1,14 @@
 pub mod handle {
-    pub use data::{DataType, Datas, Modification, Operation, Record, Records, TableOperation};
-    use std::collections::HashMap;
+    pub use data::{
+        Agg, DataType, Datas, Modification, Operation, Policy, Record, Records, TableOperation,
+    };
     use std::hash::Hash;
-    use std::sync::{Arc, RwLock};
+    use std::sync::mpsc;
+    use std::sync::Arc;
 
+    use data::{Clock, DataTypeError, SizeOf};
     use evmap;
-    use inner::srmap::SRMap;
+    use inner::srmap::{MemoryUsage, Op, Quota, QuotaError, SRMap, Update};
 
     #[derive(Clone)]
     pub struct Handle<K, V, M = ()>
@@ -16,7 +19,7 @@ pub mod handle {
     {
         pub handle: SRMap<K, V, M>,
         pub iid: usize,
-        pub umap: Arc<RwLock<HashMap<K, Vec<V>>>>,
+        pub umap: evmap::ReadHandle<K, V>,
     }
 
     impl<K, V, M> Handle<K, V, M>
@@ -26,11 +29,10 @@ pub mod handle {
         M: Clone,
     {
         pub fn clone_new_user(&mut self) -> (usize, Handle<K, V, M>, Handle<K, V, M>) {
-            let umap = Arc::new(RwLock::new(HashMap::new()));
             let mut new_handle = Handle {
                 handle: self.handle.clone(),
                 iid: 0,
-                umap: umap,
+                umap: self.handle.private_reader(0),
             };
 
             new_handle.add_user();
@@ -38,59 +40,60 @@ pub mod handle {
         }
 
         // Add the given value to the value-set of the given key.
+        //
+        // Does nothing if the user is over its quota; use `try_insert` to find out.
         pub fn insert(&mut self, k: K, v: V, uid: Option<usize>) {
-            let mut container = Vec::new();
-            container.push(v.clone());
+            let _ = self.try_insert(k, v, uid);
+        }
 
-            let success;
-            let mut _uid = self.iid;
-            match uid {
-                Some(iid) => {
-                    // if iid > 2000 {
-                    //     println!("inserting: k: {:?}, v: {:?}, id: {:?}", k, container, iid);
-                    // }
-                    _uid = iid;
-                    success = self.handle.insert(k.clone(), container, iid);
-                }
-                None => {
-                    if self.iid > 2000 {
-                        // println!(
-                        //     "inserting: k: {:?}, v: {:?}, id: {:?}",
-                        //     k, container, self.iid
-                        // );
-                    }
-                    success = self.handle.insert(k.clone(), container, self.iid);
-                }
-            }
+        /// Like `insert`, but fails if the insert would take the user over its quota.
+        pub fn try_insert(&mut self, k: K, v: V, uid: Option<usize>) -> Result<(), QuotaError> {
+            let uid = uid.unwrap_or(self.iid);
 
             // insert into umap if gmap insert didn't succeed
-            if !success {
-                // println!("user {:?} insert k {:?} into user map", _uid, k);
-                let mut add = false;
-                let mut added_vec = None;
-
-                match self.umap.write().unwrap().get_mut(&k) {
-                    Some(vec) => {
-                        vec.push(v.clone());
-                    }
-                    None => {
-                        let mut new_vec = Vec::new();
-                        new_vec.push(v.clone());
-                        add = true;
-                        added_vec = Some(new_vec);
-                    }
-                }
-
-                if add {
-                    self.umap
-                        .write()
-                        .unwrap()
-                        .insert(k.clone(), added_vec.unwrap());
-                }
+            if !self.handle.try_insert(k.clone(), vec![v.clone()], uid)? {
+                self.insert_private(k, v, uid)?;
             }
+            Ok(())
+        }
+
+        // Add a record that isn't in the global map to the user map.
+        fn insert_private(&mut self, k: K, v: V, uid: usize) -> Result<(), QuotaError> {
+            self.handle.insert_private(uid, k, v)
+        }
+
+        /// Stage a number of writes, and then apply and publish them all at once.
+        ///
+        /// The writes staged by `f` are only applied if it returns `Ok`, in which case they are
+        /// published in a single refresh, so readers see either all or none of them. If `f`
+        /// returns an error, the staged writes are abandoned.
+        ///
+        /// If the writes would take a user over its quota, they are abandoned too, and the
+        /// `QuotaError` is returned.
+        pub fn batch<F, T, E>(&mut self, f: F) -> Result<T, E>
+        where
+            F: FnOnce(&mut Batch<K, V>) -> Result<T, E>,
+            E: From<QuotaError>,
+        {
+            let mut batch = Batch {
+                iid: self.iid,
+                ops: Vec::new(),
+            };
+            let res = f(&mut batch)?;
+
+            self.handle.apply(batch.ops)?;
+            Ok(res)
+        }
+
+        /// Insert a value into the global map that expires after the given time-to-live (see
+        /// `SRMap::insert_with_ttl`).
+        pub fn insert_with_ttl(&mut self, k: K, v: V, ttl: chrono::Duration) {
+            self.handle.insert_with_ttl(k, vec![v], ttl);
         }
 
         // Replace the value-set of the given key with the given value.
+        //
+        // Does nothing if the user is over its quota.
         pub fn update(&mut self, k: K, v: V) {
             let mut container = Vec::new();
             container.push(v);
@@ -111,12 +114,95 @@ pub mod handle {
 
         pub fn add_user(&mut self) {
             self.iid = self.handle.add_user();
+            self.umap = self.handle.private_reader(self.iid);
+        }
+
+        /// Record the id that the application uses for this handle's user, which is what
+        /// `Policy::ColumnIsUser` compares rows against.
+        pub fn set_external_id(&self, id: usize) {
+            self.handle.set_external_id(self.iid, id);
         }
 
         pub fn refresh(&mut self) {
             self.handle.refresh();
         }
 
+        /// Use the given clock for records with a time-to-live (see `SRMap::set_clock`).
+        pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+            self.handle.set_clock(clock);
+        }
+
+        /// Switch the map to partial mode (see `SRMap::set_partial`).
+        pub fn set_partial<F>(&self, upcall: F)
+        where
+            F: Fn(&K) + Send + Sync + 'static,
+        {
+            self.handle.set_partial(upcall);
+        }
+
+        /// Mark the given key as filled, so that reads of it hit even if it has no values.
+        pub fn mark_filled(&mut self, k: K) {
+            self.handle.mark_filled(k);
+        }
+
+        /// Mark the given key as a hole, and drop its values.
+        pub fn mark_hole(&mut self, k: K) {
+            self.handle.mark_hole(&k);
+        }
+
+        /// Limit how many keys the global map holds (see `SRMap::set_capacity`).
+        pub fn set_capacity(&self, capacity: Option<usize>) {
+            self.handle.set_capacity(capacity);
+        }
+
+        /// Limit how much the given user can add to the map.
+        pub fn set_quota(&self, uid: usize, quota: Quota)
+        where
+            K: SizeOf,
+            V: SizeOf,
+        {
+            self.handle.set_quota(uid, quota);
+        }
+
+        /// The version of the map published by the latest refresh.
+        pub fn version(&self) -> usize {
+            self.handle.version()
+        }
+
+        /// Set how many refreshes worth of history to retain for `get_as_of`.
+        pub fn set_history_window(&self, window: usize) {
+            self.handle.set_history_window(window);
+        }
+
+        /// Get the values under the given key that this handle's user could see at the given
+        /// version of the map.
+        ///
+        /// Returns `None` if that version is not retained (see `set_history_window`). Only
+        /// records in the shared map are versioned; private records are not included.
+        pub fn get_as_of(&self, key: &K, version: usize) -> Option<Vec<V>> {
+            self.handle.get_as_of(key, self.iid, version)
+        }
+
+        /// Start a read transaction.
+        ///
+        /// All reads made through the transaction observe the same version of the shared map,
+        /// even if refreshes happen while it is open.
+        pub fn read_txn(&self) -> ReadTxn<'_, K, V, M> {
+            ReadTxn {
+                version: self.handle.pin(),
+                handle: self,
+            }
+        }
+
+        /// Subscribe to changes to the records visible to the given user (or this handle's user).
+        ///
+        /// Every refresh sends one message for each key whose visible values changed, holding
+        /// the values the user gained (`true`) or lost (`false`) access to. For `Vec<DataType>`
+        /// values, each change converts directly into a `Record`.
+        pub fn subscribe(&self, uid: Option<usize>) -> mpsc::Receiver<Update<K, V>> {
+            self.handle.subscribe(uid.unwrap_or(self.iid))
+        }
+
         pub fn empty(&mut self, k: K) {
             self.handle.remove(&k, self.iid);
         }
@@ -129,34 +215,18 @@ pub mod handle {
             self.handle.remove(&k, self.iid);
         }
 
+        /// Applies a function to the values corresponding to the key, and returns the result
+        /// along with the meta value.
+        ///
+        /// The result is `None` if the key is in neither the global map nor this handle's user's
+        /// private records, for example because it was evicted.
         pub fn meta_get_and<F, T>(&self, key: &K, then: F) -> Option<(Option<T>, M)>
         where
             K: Hash + Eq,
             F: FnOnce(&[V]) -> T,
         {
-            // get records stored in umap
-            let mut umap_res = self.umap.write().unwrap();
-            let umap_res = umap_res.get_mut(key);
-
-            let mut gmap_res = self.handle.get(key, self.iid).unwrap();
-            // println!("result: {:?}", gmap_res);
-
-            match umap_res {
-                Some(mut result) => {
-                    // println!("result: {:?}", result);
-                    gmap_res.append(&mut result);
-                }
-                None => {}
-            }
-
             let meta = self.handle.meta.clone();
-
-            if gmap_res.len() < 1 {
-                return Some((None, meta));
-            } else {
-                let gmap_res = Some(gmap_res).map(move |v| then(&*v)).unwrap();
-                return Some((Some(gmap_res), meta));
-            }
+            Some((self.get_and(key, then), meta))
         }
 
         pub fn is_empty(&self) -> bool {
@@ -177,25 +247,36 @@ pub mod handle {
         }
 
         /// Applies a function to the values corresponding to the key, and returns the result.
+        ///
+        /// Returns `None` if the key is in neither the global map nor this handle's user's
+        /// private records, for example because it was evicted. A key that is present but has no
+        /// values visible to the user is passed to `then` as an empty slice.
+        ///
+        /// In partial mode, reads of holes return `None`, and reads of filled keys never do.
         pub fn get_and<F, T>(&self, key: &K, then: F) -> Option<T>
         where
             K: Hash + Eq,
             F: FnOnce(&[V]) -> T,
         {
+            let mut res = Vec::new();
+            let shared = self
+                .handle
+                .get_and(key, self.iid, |vs| res.extend(vs.cloned()))
+                .is_some();
+            if !shared && self.handle.is_partial() {
+                return None;
+            }
+
             // get records stored in umap
-            let mut umap_res = self.umap.write().unwrap();
-            let umap_res = umap_res.get_mut(key);
-            let mut gmap_res = self.handle.get(key, self.iid).unwrap();
+            let private = self
+                .umap
+                .get_and(key, |result| res.extend_from_slice(result))
+                .is_some();
 
-            match umap_res {
-                Some(mut result) => {
-                    gmap_res.append(&mut result);
-                }
-                None => {}
+            if !shared && !private {
+                return None;
             }
-
-            let gmap_res = Some(gmap_res).map(move |v| then(&*v)).unwrap();
-            Some(gmap_res)
+            Some(then(&res))
         }
 
         fn with_handle<F, T>(&self, f: F) -> Option<T>
@@ -226,11 +307,146 @@ pub mod handle {
         }
 
         pub fn contains_key(&self, key: &K) -> bool {
-            let res = self.handle.get(key, self.iid);
-            match res {
-                Some(_r) => true,
-                None => false,
+            self.handle.contains_key(key) || self.umap.contains_key(key)
+        }
+    }
+
+    /// Writes staged to be applied together by `Handle::batch`.
+    pub struct Batch<K, V> {
+        iid: usize,
+        ops: Vec<Op<K, V>>,
+    }
+
+    impl<K, V> Batch<K, V> {
+        /// Stage an insert, which behaves like `Handle::insert`.
+        pub fn insert(&mut self, k: K, v: V, uid: Option<usize>) {
+            self.ops
+                .push(Op::InsertOrPrivate(k, v, uid.unwrap_or(self.iid)));
+        }
+
+        /// Stage giving a user access to a value in the global map.
+        ///
+        /// Only values that have already been published by a refresh can be granted access to.
+        /// Unlike `insert`, nothing is added to the user's private records if there is no
+        /// occurrence of the value that the user doesn't already have access to.
+        pub fn grant(&mut self, k: K, v: V, uid: usize) {
+            self.ops.push(Op::Insert(k, vec![v], uid));
+        }
+
+        /// Stage a remove, which behaves like `Handle::remove`.
+        pub fn remove(&mut self, k: K, uid: Option<usize>) {
+            self.ops.push(Op::Remove(k, uid.unwrap_or(self.iid)));
+        }
+    }
+
+    /// A set of mutually consistent reads, all made against the same version of the map.
+    ///
+    /// The version stays pinned (and so readable) until the transaction is dropped. Private
+    /// records are not versioned, so reads always include the user's current private records.
+    pub struct ReadTxn<'a, K, V, M>
+    where
+        K: Eq + Hash + Clone + std::fmt::Debug + 'a,
+        V: Clone + Eq + std::fmt::Debug + Hash + evmap::ShallowCopy + 'a,
+        M: Clone + 'a,
+    {
+        handle: &'a Handle<K, V, M>,
+        version: usize,
+    }
+
+    impl<'a, K, V, M> ReadTxn<'a, K, V, M>
+    where
+        K: Eq + Hash + Clone + std::fmt::Debug,
+        V: Clone + Eq + std::fmt::Debug + Hash + evmap::ShallowCopy,
+        M: Clone,
+    {
+        /// The version of the map this transaction reads.
+        pub fn version(&self) -> usize {
+            self.version
+        }
+
+        /// Applies a function to the values corresponding to the key as of this transaction's
+        /// version, and returns the result.
+        ///
+        /// Like `Handle::get_and`, returns `None` if the key was in neither the global map (at
+        /// this version) nor this handle's user's private records.
+        pub fn get_and<F, T>(&self, key: &K, then: F) -> Option<T>
+        where
+            F: FnOnce(&[V]) -> T,
+        {
+            let handle = self.handle;
+            let shared = handle
+                .handle
+                .lookup_as_of(key, handle.iid, self.version)
+                .and_then(|vs| vs);
+            if shared.is_none() && handle.handle.is_partial() {
+                return None;
+            }
+            let shared_hit = shared.is_some();
+            let mut res = shared.unwrap_or_else(Vec::new);
+            let private = handle
+                .umap
+                .get_and(key, |private| res.extend_from_slice(private))
+                .is_some();
+
+            if !shared_hit && !private {
+                return None;
             }
+            Some(then(&res))
+        }
+    }
+
+    impl<'a, K, V, M> Drop for ReadTxn<'a, K, V, M>
+    where
+        K: Eq + Hash + Clone + std::fmt::Debug,
+        V: Clone + Eq + std::fmt::Debug + Hash + evmap::ShallowCopy,
+        M: Clone,
+    {
+        fn drop(&mut self) {
+            self.handle.handle.unpin(self.version);
+        }
+    }
+
+    impl<K, V, M> Handle<K, V, M>
+    where
+        K: Eq + Hash + Clone + std::fmt::Debug + SizeOf,
+        V: Clone + Eq + std::fmt::Debug + Hash + evmap::ShallowCopy + SizeOf,
+        M: Clone,
+    {
+        /// Report how much memory the underlying map uses (see `SRMap::memory_usage`).
+        pub fn memory_usage(&self) -> MemoryUsage {
+            self.handle.memory_usage()
+        }
+    }
+
+    impl<K, M> Handle<K, Vec<DataType>, M>
+    where
+        K: Eq + Hash + Clone + std::fmt::Debug,
+        M: Clone,
+    {
+        /// Compute the given aggregation over `column` of the rows stored under `key`.
+        ///
+        /// Only rows that this handle's user can see (including its private rows) contribute to
+        /// the result. Returns `Ok(None)` if the key is missing, and an error if the aggregation
+        /// fails (see `Agg::apply`).
+        pub fn aggregate(
+            &self,
+            key: &K,
+            column: usize,
+            agg: Agg,
+        ) -> Result<Option<DataType>, DataTypeError> {
+            match self.get_and(key, |rows| agg.apply(rows, column)) {
+                Some(res) => res.map(Some),
+                None => Ok(None),
+            }
+        }
+
+        /// Register a row-level security policy (see `SRMap::add_policy`).
+        ///
+        /// The policy sees each user's external id (see `set_external_id`), or its internal id
+        /// if it has none.
+        pub fn add_policy(&mut self, policy: Policy) {
+            self.handle
+                .add_policy(move |row: &Vec<DataType>, id| policy.allows(row, id));
         }
     }
 }
@@ -9,14 +9,20 @@
 
 pub mod srmap {
     use evmap;
-    use std::collections::HashMap;
-    use std::hash::Hash;
-    use std::sync::Mutex;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+    use std::hash::{Hash, Hasher};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::sync::{Mutex, MutexGuard};
     use std::sync::{Arc, RwLock};
     use bit_vec::BitVec;
+    use chrono::{Duration, NaiveDateTime};
+    use rand::{self, Rng};
 
 
     pub use data::{DataType, Datas, Modification, Operation, Record, Records, TableOperation};
+    use data::{Clock, SizeOf, SystemClock};
 
     // Bitmap update functions
     pub fn update_access(bitmap: &mut BitVec, uid: usize, add: bool) {
@@ -47,6 +53,675 @@ pub mod srmap {
         }
     }
 
+    /// The changes to one key that became visible to a subscribed user during a refresh.
+    ///
+    /// Each value is paired with `true` if the user gained access to it, and `false` if it lost
+    /// access to it.
+    pub type Update<K, V> = (K, Vec<(V, bool)>);
+
+    // A user that wants to be told about changes to the records it can see.
+    struct Subscriber<K, V> {
+        uid: usize,
+        tx: mpsc::Sender<Update<K, V>>,
+        // private records inserted since the last refresh
+        private: Vec<(K, V)>,
+    }
+
+    // What the keys changed by recent refreshes looked like, so that reads can be answered as of
+    // an older version of the map.
+    struct History<K, V> {
+        // how many refreshes to keep snapshots for
+        window: usize,
+        // for each retained refresh, the version it published, when the version before it was
+        // published, and every occurrence of every key it changed just before it was published
+        // (`None` for keys that weren't in the map)
+        entries: VecDeque<(usize, Option<NaiveDateTime>, HashMap<K, Option<Vec<Entry<V>>>>)>,
+        // when the current version was published; `None` until the first refresh
+        published: Option<NaiveDateTime>,
+        // versions that open read transactions are reading, with how many transactions read each
+        pinned: BTreeMap<usize, usize>,
+    }
+
+    impl<K, V> History<K, V> {
+        fn recording(&self) -> bool {
+            self.window > 0 || !self.pinned.is_empty()
+        }
+
+        // Drop snapshots that are outside the window, unless a pinned version still needs them.
+        fn prune(&mut self) {
+            while self.entries.len() > self.window {
+                let needed = match (self.entries.front(), self.pinned.keys().next()) {
+                    (Some(&(version, _, _)), Some(&oldest_pin)) => oldest_pin < version,
+                    _ => false,
+                };
+                if needed {
+                    break;
+                }
+                self.entries.pop_front();
+            }
+        }
+    }
+
+    /// One occurrence of a value in the global map, along with the bitmap of users that can see it.
+    #[derive(Clone, PartialEq, Eq, Hash, Debug)]
+    pub struct Entry<V> {
+        pub value: V,
+        // private, so that nothing outside this module can change a bitmap in place (see
+        // `shallow_copy`)
+        access: BitVec,
+        /// When the occurrence expires, if ever.
+        pub expires: Option<NaiveDateTime>,
+    }
+
+    impl<V> Entry<V> {
+        /// Whether the occurrence has not expired by the given time.
+        pub fn is_live(&self, now: NaiveDateTime) -> bool {
+            self.expires.map_or(true, |expires| expires > now)
+        }
+
+        /// The bitmap of users that can see the occurrence.
+        pub fn access(&self) -> &BitVec {
+            &self.access
+        }
+    }
+
+    impl<V> evmap::ShallowCopy for Entry<V>
+    where
+        V: evmap::ShallowCopy,
+    {
+        unsafe fn shallow_copy(&mut self) -> Self {
+            // both copies can share the bitmap's storage, since entries in the map are never
+            // modified in place: access changes clone the entry, and replace the old one with it
+            Entry {
+                value: self.value.shallow_copy(),
+                access: std::ptr::read(&self.access),
+                expires: self.expires,
+            }
+        }
+    }
+
+    /// A rule that decides whether the given user can see the given value.
+    pub type PolicyFn<V> = Arc<dyn Fn(&V, usize) -> bool + Send + Sync>;
+
+    // The policies in effect, and the users they have been applied to, as of the latest refresh.
+    struct PolicySet<V> {
+        policies: Vec<PolicyFn<V>>,
+        // policies have been applied to the users below this
+        users: usize,
+        // the ids that policies know users by, for users that have one
+        ids: HashMap<usize, usize>,
+    }
+
+    impl<V> PolicySet<V> {
+        // Whether any of the given policies allows the user to see the value.
+        fn allows(&self, policies: &[PolicyFn<V>], v: &V, uid: usize) -> bool {
+            let id = self.ids.get(&uid).cloned().unwrap_or(uid);
+            policies.iter().any(|policy| policy(v, id))
+        }
+
+        // The bitmap of a new occurrence of the value, which the policies decide.
+        fn access(&self, v: &V) -> BitVec {
+            let mut access = BitVec::from_elem(1, false);
+            if !self.policies.is_empty() {
+                for uid in (1..self.users).filter(|&uid| self.allows(&self.policies, v, uid)) {
+                    update_access(&mut access, uid, true);
+                }
+            }
+            access
+        }
+
+        // The bitmap of the occurrence after applying the policies from index `old` on to every
+        // user, and all of the policies to the `changed` users, or `None` if it doesn't change.
+        fn newly_allowed(
+            &self,
+            e: &Entry<V>,
+            old: usize,
+            changed: &HashSet<usize>,
+        ) -> Option<BitVec> {
+            let added = &self.policies[old..];
+            let mut access: Option<BitVec> = None;
+            let mut check = |uid: usize| {
+                let policies = if changed.contains(&uid) {
+                    &self.policies[..]
+                } else {
+                    added
+                };
+                if !get_access(&e.access, uid) && self.allows(policies, &e.value, uid) {
+                    update_access(access.get_or_insert_with(|| e.access.clone()), uid, true);
+                }
+            };
+            if added.is_empty() {
+                changed.iter().for_each(|&uid| check(uid));
+            } else {
+                (1..self.users).for_each(check);
+            }
+            access
+        }
+    }
+
+    type Policies<V> = Arc<RwLock<PolicySet<V>>>;
+
+    // The index of a published, live occurrence of the value that the user can't see yet, which
+    // is the occurrence an insert by the user gives it access to.
+    fn grantable<V: Eq>(
+        entries: &[Entry<V>],
+        published: usize,
+        v: &V,
+        uid: usize,
+        now: NaiveDateTime,
+    ) -> Option<usize> {
+        entries
+            .iter()
+            .take(published)
+            .position(|e| &e.value == v && e.is_live(now) && !get_access(&e.access, uid))
+    }
+
+    // Every key in a shard, so that a random one can be picked for eviction.
+    struct KeyIndex<K: Eq + Hash> {
+        keys: Vec<K>,
+        index: HashMap<K, usize>,
+    }
+
+    impl<K: Eq + Hash + Clone> KeyIndex<K> {
+        fn insert(&mut self, k: &K) {
+            if !self.index.contains_key(k) {
+                self.index.insert(k.clone(), self.keys.len());
+                self.keys.push(k.clone());
+            }
+        }
+
+        fn remove(&mut self, k: &K) {
+            if let Some(i) = self.index.remove(k) {
+                self.keys.swap_remove(i);
+                if let Some(moved) = self.keys.get(i) {
+                    self.index.insert(moved.clone(), i);
+                }
+            }
+        }
+    }
+
+    /// The write half of a shard of an `SRMap`, shared by all of its handles.
+    pub struct Writer<K, V>
+    where
+        K: Eq + Hash + Clone,
+        V: Clone + Eq + Hash + evmap::ShallowCopy,
+    {
+        pub map_w: evmap::WriteHandle<K, Entry<V>>,
+        // the entries of keys written since the last refresh, which aren't visible through the
+        // read handles yet
+        pending: HashMap<K, Vec<Entry<V>>>,
+        // keys written since the last refresh
+        dirty: HashSet<K>,
+        // keys emptied since the last refresh, whose published entries are no longer in the map
+        emptied: HashSet<K>,
+        // only kept while the map has a capacity
+        keys: Option<KeyIndex<K>>,
+        // keys marked as filled (`true`) or as holes (`false`) since the last refresh
+        marks: HashMap<K, bool>,
+        // the keys that are filled, which every refresh publishes to the shard's `filled`
+        filled_w: evmap::WriteHandle<K, bool>,
+        // the keys with occurrences that expire at each time
+        expiring: BTreeMap<NaiveDateTime, Vec<K>>,
+        clock: Arc<RwLock<Arc<dyn Clock>>>,
+        // shared with the `SRMap`, so that new values get the access the policies give them
+        policies: Policies<V>,
+        // how many occurrences in this shard each user without a quota has been given access
+        // to, counted here so that their writes don't contend on `Quotas`
+        grants: HashMap<usize, usize>,
+        // users with a quota, whose grants are counted in `Quotas` instead
+        limited: HashSet<usize>,
+    }
+
+    impl<K, V> Writer<K, V>
+    where
+        K: Eq + Hash + Clone,
+        V: Clone + Eq + Hash + evmap::ShallowCopy,
+    {
+        // The entries under a key, including unrefreshed writes.
+        fn entries(&mut self, k: &K) -> &mut Vec<Entry<V>> {
+            if !self.pending.contains_key(k) {
+                let published = self.map_w.get_and(k, |s| s.to_vec()).unwrap_or_else(Vec::new);
+                self.pending.insert(k.clone(), published);
+            }
+            self.pending.get_mut(k).unwrap()
+        }
+
+        // A copy of the entries under a key, including unrefreshed writes, along with how many
+        // of them have been published.
+        fn peek(&self, k: &K) -> (usize, Vec<Entry<V>>) {
+            match self.pending.get(k) {
+                Some(entries) => (self.published(k), entries.clone()),
+                None => {
+                    let published = self
+                        .map_w
+                        .get_and(k, |s| s.to_vec())
+                        .unwrap_or_else(Vec::new);
+                    (published.len(), published)
+                }
+            }
+        }
+
+        // How many of the key's entries have been published, which is none if the key has been
+        // emptied since.
+        fn published(&self, k: &K) -> usize {
+            if self.emptied.contains(k) {
+                return 0;
+            }
+            self.map_w.get_and(k, |s| s.len()).unwrap_or(0)
+        }
+
+        // Drop every entry under the key, and return them.
+        fn empty(&mut self, k: &K) -> Vec<Entry<V>> {
+            let entries = std::mem::replace(self.entries(k), Vec::new());
+            self.map_w.empty(k.clone());
+            self.emptied.insert(k.clone());
+            entries
+        }
+
+        // Change who can see the entry at the given index of the key's entries.
+        fn set_access(&mut self, k: &K, i: usize, uid: usize, add: bool) {
+            let mut new = self.entries(k)[i].clone();
+            update_access(&mut new.access, uid, add);
+            self.replace(k, i, new);
+        }
+
+        fn replace(&mut self, k: &K, i: usize, new: Entry<V>) {
+            let old = self.entries(k)[i].clone();
+            self.map_w.remove(k.clone(), old);
+            self.map_w.insert(k.clone(), new.clone());
+            self.entries(k)[i] = new;
+        }
+
+        // Whether the user has a quota, and so has its grants counted in `Quotas`.
+        fn limited(&self, uid: usize) -> bool {
+            uid != 0 && self.limited.contains(&uid)
+        }
+
+        fn add_grants(&mut self, uid: usize, granted: usize) {
+            if uid != 0 && granted > 0 {
+                *self.grants.entry(uid).or_insert(0) += granted;
+            }
+        }
+
+        fn remove_grants(&mut self, uid: usize, revoked: usize) {
+            if let Some(used) = self.grants.get_mut(&uid) {
+                *used = used.saturating_sub(revoked);
+            }
+        }
+
+        // Grants at most `grants_left` occurrences, and decrements it for every grant made.
+        fn insert(
+            &mut self,
+            k: K,
+            v: Vec<V>,
+            uid: usize,
+            grants_left: &mut usize,
+        ) -> Result<bool, QuotaError> {
+            self.dirty.insert(k.clone());
+            // global map insert.
+            if uid == 0 as usize {
+                self.insert_global(k, v, None);
+                return Ok(true);
+            } else {
+                // if value exists in the global map, remove this user's name from restricted access list.
+                // otherwise, the caller adds the record to the user's umap.
+                let mut res = false;
+                // only occurrences that have been published can be granted access to, and new
+                // occurrences are always at the end of the pending entries
+                let published = self.published(&k);
+                let now = self.clock.read().unwrap().now().naive_utc();
+                for val in v {
+                    // find an occurrence of the value _that this user can not yet see_, and
+                    // grant access to it.
+                    let i = grantable(self.entries(&k), published, &val, uid, now);
+                    if let Some(i) = i {
+                        if *grants_left == 0 {
+                            return Err(QuotaError::Grants);
+                        }
+                        self.set_access(&k, i, uid, true);
+                        *grants_left -= 1;
+                        res = true;
+                    }
+                }
+                return Ok(res);
+            }
+        }
+
+        fn insert_global(&mut self, k: K, v: Vec<V>, expires: Option<NaiveDateTime>) {
+            self.dirty.insert(k.clone());
+            if let Some(ref mut keys) = self.keys {
+                keys.insert(&k);
+            }
+            if let Some(expires) = expires {
+                self.expiring.entry(expires).or_insert_with(Vec::new).push(k.clone());
+            }
+            let policies = self.policies.clone();
+            let policies = policies.read().unwrap();
+            for val in v {
+                // users added since the last refresh get access when it applies the policies to
+                // them
+                let entry = Entry {
+                    access: policies.access(&val),
+                    value: val,
+                    expires: expires,
+                };
+                self.map_w.insert(k.clone(), entry.clone());
+                self.entries(&k).push(entry);
+            }
+        }
+
+        // Start keeping track of every key, including unrefreshed ones, so that they can be
+        // evicted.
+        fn index_keys(&mut self) {
+            let mut keys = KeyIndex {
+                keys: Vec::new(),
+                index: HashMap::new(),
+            };
+            for (k, entries) in &self.pending {
+                if !entries.is_empty() {
+                    keys.insert(k);
+                }
+            }
+            let pending = &self.pending;
+            self.map_w.for_each(|k, _| {
+                if !pending.contains_key(k) {
+                    keys.insert(k);
+                }
+            });
+            self.keys = Some(keys);
+        }
+
+        fn key_count(&self) -> usize {
+            self.keys.as_ref().map_or(0, |keys| keys.keys.len())
+        }
+
+        // Remove every occurrence that has expired by the given time, and return them.
+        fn reap(&mut self, now: NaiveDateTime) -> Vec<Entry<V>> {
+            let mut reaped = Vec::new();
+            loop {
+                let expires = match self.expiring.keys().next() {
+                    Some(&expires) if expires <= now => expires,
+                    _ => break,
+                };
+                for k in self.expiring.remove(&expires).unwrap() {
+                    // the key may have been evicted since, in which case nothing is left to reap
+                    let (live, expired): (Vec<_>, Vec<_>) =
+                        self.entries(&k).drain(..).partition(|e| e.is_live(now));
+                    if expired.is_empty() {
+                        self.pending.insert(k, live);
+                        continue;
+                    }
+                    for e in &expired {
+                        self.map_w.remove(k.clone(), e.clone());
+                    }
+                    if live.is_empty() {
+                        if let Some(ref mut keys) = self.keys {
+                            keys.remove(&k);
+                        }
+                        self.empty(&k);
+                    } else {
+                        self.pending.insert(k.clone(), live);
+                    }
+                    self.dirty.insert(k);
+                    reaped.extend(expired);
+                }
+            }
+            reaped
+        }
+
+        // Evict the key at the given index of the key index, and return its entries.
+        fn evict(&mut self, i: usize) -> Vec<Entry<V>> {
+            let k = self.keys.as_ref().unwrap().keys[i].clone();
+            self.mark_hole(&k)
+        }
+
+        // Turn the key into a hole, and return the entries it held.
+        fn mark_hole(&mut self, k: &K) -> Vec<Entry<V>> {
+            self.marks.insert(k.clone(), false);
+            if !self.map_w.contains_key(k) && !self.pending.contains_key(k) {
+                return Vec::new();
+            }
+            if let Some(ref mut keys) = self.keys {
+                keys.remove(k);
+            }
+            self.dirty.insert(k.clone());
+            self.empty(k)
+        }
+
+        // Give users access to what the policies newly allow them to see (see
+        // `PolicySet::newly_allowed`).
+        fn apply_policies(
+            &mut self,
+            policies: &PolicySet<V>,
+            old: usize,
+            changed: &HashSet<usize>,
+        ) {
+            let mut allowed = Vec::new();
+            {
+                let mut check = |k: &K, entries: &[Entry<V>]| {
+                    for (i, e) in entries.iter().enumerate() {
+                        if let Some(access) = policies.newly_allowed(e, old, changed) {
+                            allowed.push((k.clone(), i, access));
+                        }
+                    }
+                };
+                for (k, entries) in &self.pending {
+                    check(k, entries);
+                }
+                let pending = &self.pending;
+                self.map_w.for_each(|k, entries| {
+                    if !pending.contains_key(k) {
+                        check(k, entries);
+                    }
+                });
+            }
+            for (k, i, access) in allowed {
+                let new = Entry {
+                    access: access,
+                    ..self.entries(&k)[i].clone()
+                };
+                self.replace(&k, i, new);
+                self.dirty.insert(k);
+            }
+        }
+
+        // Returns how many occurrences the user lost access to.
+        fn remove(&mut self, k: &K, uid: usize) -> usize {
+            self.dirty.insert(k.clone());
+
+            // revoke this user's access to every occurrence of every value under the key, except
+            // those that a policy allows it to see
+            let policies = self.policies.clone();
+            let policies = policies.read().unwrap();
+            let granted: Vec<usize> = self
+                .entries(k)
+                .iter()
+                .enumerate()
+                .filter(|&(_, e)| {
+                    e.access.get(uid) == Some(true)
+                        && !policies.allows(&policies.policies, &e.value, uid)
+                })
+                .map(|(i, _)| i)
+                .collect();
+            for &i in &granted {
+                self.set_access(k, i, uid, false);
+            }
+            granted.len()
+        }
+    }
+
+    /// Limits on how much a single user can add to the map.
+    ///
+    /// A limit of `None` means there is no limit.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Quota {
+        /// How many private records the user can have.
+        pub private_records: Option<usize>,
+        /// How many bytes of private records (keys and values, as measured by `SizeOf`) the user
+        /// can have.
+        pub private_bytes: Option<usize>,
+        /// How many occurrences of values in the global map the user can have access to.
+        ///
+        /// Only access given by the user's own inserts counts; values that a policy lets the
+        /// user see don't.
+        pub grants: Option<usize>,
+    }
+
+    /// The error returned when a write would take a user over its quota.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum QuotaError {
+        /// The user has as many private records as `Quota::private_records` allows.
+        PrivateRecords,
+        /// The record would take the user's private records over `Quota::private_bytes`.
+        PrivateBytes,
+        /// The user has access to as many global values as `Quota::grants` allows.
+        Grants,
+    }
+
+    impl std::fmt::Display for QuotaError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match *self {
+                QuotaError::PrivateRecords => write!(f, "private record quota exceeded"),
+                QuotaError::PrivateBytes => write!(f, "private byte quota exceeded"),
+                QuotaError::Grants => write!(f, "grant quota exceeded"),
+            }
+        }
+    }
+
+    impl std::error::Error for QuotaError {}
+
+    // What each user has used of its quota.
+    #[derive(Clone, Default)]
+    struct QuotaUsage {
+        private_records: usize,
+        private_bytes: usize,
+        grants: usize,
+    }
+
+    struct Quotas<K, V> {
+        limits: HashMap<usize, Quota>,
+        used: HashMap<usize, QuotaUsage>,
+        // measures private records, once a quota has been set
+        size_of: Option<Arc<dyn Fn(&K, &V) -> usize + Send + Sync>>,
+    }
+
+    impl<K, V> Quotas<K, V> {
+        fn grants_left(&self, uid: usize) -> usize {
+            match self.limits.get(&uid).and_then(|q| q.grants) {
+                Some(limit) => {
+                    let used = self.used.get(&uid).map_or(0, |u| u.grants);
+                    limit.saturating_sub(used)
+                }
+                None => usize::max_value(),
+            }
+        }
+
+        fn add_grants(&mut self, uid: usize, granted: usize) {
+            if uid != 0 {
+                self.used.entry(uid).or_insert_with(QuotaUsage::default).grants += granted;
+            }
+        }
+
+        fn remove_grants(&mut self, uid: usize, revoked: usize) {
+            if let Some(used) = self.used.get_mut(&uid) {
+                used.grants = used.grants.saturating_sub(revoked);
+            }
+        }
+
+        // Account for a new private record, unless that would take the user over its quota.
+        fn add_private(&mut self, uid: usize, k: &K, v: &V) -> Result<(), QuotaError> {
+            let mut used = self.used.get(&uid).cloned().unwrap_or_default();
+            self.add_private_to(&mut used, uid, k, v)?;
+            self.used.insert(uid, used);
+            Ok(())
+        }
+
+        // Like `add_private`, but accounts for the record in the given usage of the user.
+        fn add_private_to(
+            &self,
+            used: &mut QuotaUsage,
+            uid: usize,
+            k: &K,
+            v: &V,
+        ) -> Result<(), QuotaError> {
+            let bytes = self.size_of.as_ref().map_or(0, |size_of| size_of(k, v));
+            if let Some(limit) = self.limits.get(&uid) {
+                if limit.private_records.map_or(false, |l| used.private_records >= l) {
+                    return Err(QuotaError::PrivateRecords);
+                }
+                if limit.private_bytes.map_or(false, |l| used.private_bytes + bytes > l) {
+                    return Err(QuotaError::PrivateBytes);
+                }
+            }
+            used.private_records += 1;
+            used.private_bytes += bytes;
+            Ok(())
+        }
+    }
+
+    /// A write to apply as part of a batch (see `SRMap::apply`).
+    #[derive(Clone, Debug)]
+    pub enum Op<K, V> {
+        /// Insert values on behalf of a user, like `SRMap::insert`.
+        Insert(K, Vec<V>, usize),
+        /// Insert a value on behalf of a user, like `Handle::insert`: the user is given access to
+        /// an occurrence of the value in the global map, or, if there is none, the value is added
+        /// to the user's private records.
+        InsertOrPrivate(K, V, usize),
+        /// Revoke a user's access to a key, like `SRMap::remove`.
+        Remove(K, usize),
+    }
+
+    // The records of one user that have no match in the global map, and so only it can see.
+    #[derive(Clone)]
+    struct PrivateMap<K, V>
+    where
+        K: Eq + Hash + Clone,
+        V: Clone + Eq + Hash + evmap::ShallowCopy,
+    {
+        r: evmap::ReadHandle<K, V>,
+        w: Arc<Mutex<evmap::WriteHandle<K, V>>>,
+    }
+
+    /// One shard of an `SRMap`, which holds the keys that hash to it.
+    #[derive(Clone)]
+    pub struct Shard<K, V>
+    where
+        K: Eq + Hash + Clone,
+        V: Clone + Eq + Hash + evmap::ShallowCopy,
+    {
+        pub map_r: evmap::ReadHandle<K, Entry<V>>,
+        pub global_w: Arc<Mutex<Writer<K, V>>>,
+        // the keys that have been filled, as of the latest refresh
+        filled: evmap::ReadHandle<K, bool>,
+    }
+
+    /// How much memory an `SRMap` uses, in bytes, as of the latest refresh.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct MemoryUsage {
+        /// The keys and values of the global map.
+        pub values: usize,
+        /// The access bitmaps of the values in the global map.
+        pub bitmaps: usize,
+        /// The keys and values of every user's private records.
+        pub private: usize,
+        /// The bookkeeping of user ids.
+        pub ids: usize,
+        /// The index of keys that eviction picks from, which is only kept while the map has a
+        /// capacity.
+        pub key_index: usize,
+        /// For each user, the size of its private records, plus one bit for every value in the
+        /// global map that it has been given access to.
+        pub per_user: HashMap<usize, usize>,
+    }
+
+    impl MemoryUsage {
+        /// The total size of the map, which `per_user` is already included in.
+        pub fn total(&self) -> usize {
+            self.values + self.bitmaps + self.private + self.ids + self.key_index
+        }
+    }
+
     // SRMap inner structure
     pub struct SRMap<K, V, M>
     where
@@ -54,18 +729,31 @@ pub mod srmap {
         V: Clone + Eq + std::fmt::Debug + Hash + evmap::ShallowCopy,
         M: Clone,
     {
-        pub g_map_r: evmap::ReadHandle<K, V>,
-        pub b_map_r: evmap::ReadHandle<(K, V), Vec<BitVec>>,
-        pub global_w: Arc<
-            Mutex<(
-                evmap::WriteHandle<K, V>,
-                evmap::WriteHandle<(K, V), Vec<BitVec>>,
-            )>,
-        >,
+        pub shards: Vec<Shard<K, V>>,
         pub id_store: Arc<RwLock<HashMap<usize, usize>>>,
         pub meta: M,
+        private: Arc<RwLock<HashMap<usize, PrivateMap<K, V>>>>,
+        // users whose private maps have been written to since the last refresh
+        private_dirty: Arc<Mutex<HashSet<usize>>>,
         largest: Arc<RwLock<usize>>,
         g_records: usize,
+        subscribers: Arc<Mutex<Vec<Subscriber<K, V>>>>,
+        version: Arc<AtomicUsize>,
+        history: Arc<RwLock<History<K, V>>>,
+        quotas: Arc<Mutex<Quotas<K, V>>>,
+        // the most keys the global map holds after a refresh
+        capacity: Arc<RwLock<Option<usize>>>,
+        policies: Policies<V>,
+        // policies added since the last refresh, which take effect on the next one
+        pending_policies: Arc<Mutex<Vec<PolicyFn<V>>>>,
+        // external ids set since the last refresh, which policies see from the next one on
+        pending_ids: Arc<Mutex<HashMap<usize, usize>>>,
+        // whether the map is in partial mode, which reads check without taking a lock
+        partial: Arc<AtomicBool>,
+        // called on reads of holes; only set in partial mode
+        upcall: Arc<RwLock<Option<Arc<dyn Fn(&K) + Send + Sync>>>>,
+        // tells the time for expiring records
+        clock: Arc<RwLock<Arc<dyn Clock>>>,
         // log: slog::Logger,
     }
 
@@ -78,13 +766,24 @@ pub mod srmap {
         fn clone(&self) -> Self {
             // let logger = super::logger_pls();
             SRMap {
-                g_map_r: self.g_map_r.clone(),
-                b_map_r: self.b_map_r.clone(),
-                global_w: self.global_w.clone(),
+                shards: self.shards.clone(),
                 id_store: self.id_store.clone(),
                 largest: self.largest.clone(),
                 meta: self.meta.clone(),
+                private: self.private.clone(),
+                private_dirty: self.private_dirty.clone(),
                 g_records: self.g_records.clone(),
+                subscribers: self.subscribers.clone(),
+                version: self.version.clone(),
+                history: self.history.clone(),
+                quotas: self.quotas.clone(),
+                capacity: self.capacity.clone(),
+                upcall: self.upcall.clone(),
+                clock: self.clock.clone(),
+                policies: self.policies.clone(),
+                pending_policies: self.pending_policies.clone(),
+                pending_ids: self.pending_ids.clone(),
+                partial: self.partial.clone(),
                 // log: logger,
             }
         }
@@ -97,24 +796,111 @@ pub mod srmap {
         M: Clone,
     {
         pub fn new(init_m: M) -> SRMap<K, V, M> {
+            SRMap::with_shards(init_m, 1)
+        }
+
+        /// Create a map that spreads its keys across the given number of shards.
+        ///
+        /// Each shard has its own write lock, so writes to keys in different shards can proceed
+        /// in parallel. A map always has at least one shard.
+        ///
+        /// A refresh publishes the shards one after the other. Reads of a single key, `get_all`,
+        /// and reads at a version (`get_as_of`) see either none or all of a refresh, but a read
+        /// of a key in one shard can see a refresh that a later read of a key in another shard
+        /// does not see yet.
+        pub fn with_shards(init_m: M, shards: usize) -> SRMap<K, V, M> {
+            let shards = std::cmp::max(shards, 1);
             // let logger = super::logger_pls();
-            let (g_map_r, g_map_w) = evmap::new();
-            let (b_map_r, b_map_w) = evmap::new();
+            let largest = Arc::new(RwLock::new(0 as usize));
+            let policies = Arc::new(RwLock::new(PolicySet {
+                policies: Vec::new(),
+                users: 0,
+                ids: HashMap::new(),
+            }));
+            let clock: Arc<RwLock<Arc<dyn Clock>>> = Arc::new(RwLock::new(Arc::new(SystemClock)));
+            let shards = (0..shards)
+                .map(|_| {
+                    let (map_r, map_w) = evmap::new();
+                    let (filled_r, filled_w) = evmap::new();
+                    Shard {
+                        map_r: map_r,
+                        filled: filled_r,
+                        global_w: Arc::new(Mutex::new(Writer {
+                            map_w: map_w,
+                            pending: HashMap::new(),
+                            dirty: HashSet::new(),
+                            emptied: HashSet::new(),
+                            keys: None,
+                            marks: HashMap::new(),
+                            filled_w: filled_w,
+                            expiring: BTreeMap::new(),
+                            policies: policies.clone(),
+                            clock: clock.clone(),
+                            grants: HashMap::new(),
+                            limited: HashSet::new(),
+                        })),
+                    }
+                })
+                .collect();
             SRMap {
-                g_map_r: g_map_r,
-                global_w: Arc::new(Mutex::new((g_map_w, b_map_w))),
-                b_map_r: b_map_r,
+                shards: shards,
                 id_store: Arc::new(RwLock::new(HashMap::new())),
                 meta: init_m,
+                private: Arc::new(RwLock::new(HashMap::new())),
+                private_dirty: Arc::new(Mutex::new(HashSet::new())),
                 g_records: 0,
-                largest: Arc::new(RwLock::new(0 as usize)),
+                largest: largest,
+                subscribers: Arc::new(Mutex::new(Vec::new())),
+                version: Arc::new(AtomicUsize::new(0)),
+                history: Arc::new(RwLock::new(History {
+                    window: 0,
+                    entries: VecDeque::new(),
+                    published: None,
+                    pinned: BTreeMap::new(),
+                })),
+                quotas: Arc::new(Mutex::new(Quotas {
+                    limits: HashMap::new(),
+                    used: HashMap::new(),
+                    size_of: None,
+                })),
+                capacity: Arc::new(RwLock::new(None)),
+                upcall: Arc::new(RwLock::new(None)),
+                clock: clock,
+                policies: policies,
+                pending_policies: Arc::new(Mutex::new(Vec::new())),
+                pending_ids: Arc::new(Mutex::new(HashMap::new())),
+                partial: Arc::new(AtomicBool::new(false)),
                 // log: logger,
             }
         }
 
+        fn shard_index(&self, k: &K) -> usize {
+            if self.shards.len() == 1 {
+                return 0;
+            }
+            let mut hasher = DefaultHasher::new();
+            k.hash(&mut hasher);
+            hasher.finish() as usize % self.shards.len()
+        }
+
+        fn shard(&self, k: &K) -> &Shard<K, V> {
+            &self.shards[self.shard_index(k)]
+        }
+
+        // Lock the writers of every shard, always in the same order, so that callers that need
+        // more than one of them can't deadlock.
+        fn lock_all(&self) -> Vec<MutexGuard<Writer<K, V>>> {
+            self.shards
+                .iter()
+                .map(|shard| shard.global_w.lock().unwrap())
+                .collect()
+        }
+
         pub fn g_map_size(&self) -> usize {
             let mut gm_vec = Vec::new();
-            self.g_map_r.for_each(|_, _| gm_vec.push(1));
+            for shard in &self.shards {
+                shard.map_r.for_each(|_, _| gm_vec.push(1));
+            }
             gm_vec.len()
         }
 
@@ -127,173 +913,735 @@ pub mod srmap {
         }
 
         pub fn refresh(&mut self) {
-            let (ref mut g_map_w, ref mut b_map_w) = *self.global_w.lock().unwrap();
-            g_map_w.refresh();
-            b_map_w.refresh();
+            let mut writers = self.lock_all();
+            self.publish(&mut writers);
         }
 
-        pub fn insert(&mut self, k: K, v: Vec<V>, uid: usize) -> bool {
-            let (ref mut g_map_w, ref mut b_map_w) = *self.global_w.lock().unwrap();
-            // global map insert.
-            if uid == 0 as usize {
-                for val in v.clone() {
-                    self.g_records += 1;
-                    g_map_w.insert(k.clone(), val.clone());
-                    let mut outer = Vec::new();
-                    let mut buffer = Vec::new();
-                    let mut bit_map = BitVec::new();
-                    bit_map.push(false);
-                    b_map_w.get_and(&(k.clone(), val.clone()), |s| {
-                        if s.len() > 0 {
-                            outer.push(s[0].clone());
-                        } else {
-                            outer.push(Vec::new());
-                        }
-                    });
-                    if outer.len() > 0 {
-                        buffer = outer[0].to_vec();
+        /// Limit how many keys the global map holds, or lift the limit with `None`.
+        ///
+        /// Whenever a refresh would leave more keys than that, randomly chosen keys are evicted,
+        /// along with every value under them, until the map is back at capacity. Reads of evicted
+        /// keys miss, just like reads of keys that were never inserted.
+        pub fn set_capacity(&self, capacity: Option<usize>) {
+            // the keys are only indexed while there is a capacity to evict them for
+            let mut writers = self.lock_all();
+            for w in writers.iter_mut() {
+                match capacity {
+                    Some(_) if w.keys.is_none() => w.index_keys(),
+                    Some(_) => {}
+                    None => w.keys = None,
+                }
+            }
+            *self.capacity.write().unwrap() = capacity;
+        }
+
+        // Evict random keys until the map is within its capacity.
+        fn evict(&self, writers: &mut [MutexGuard<Writer<K, V>>]) {
+            let capacity = match *self.capacity.read().unwrap() {
+                Some(capacity) => capacity,
+                None => return,
+            };
+            let mut total: usize = writers.iter().map(|w| w.key_count()).sum();
+            let mut rng = rand::thread_rng();
+            while total > capacity {
+                let mut i = rng.gen_range(0, total);
+                for w in writers.iter_mut() {
+                    if i < w.key_count() {
+                        let entries = w.evict(i);
+                        self.release_grants(w, entries);
+                        break;
                     }
-                    buffer.push(bit_map);
-                    b_map_w.update((k.clone(), val.clone()), buffer);
+                    i -= w.key_count();
+                }
+                total -= 1;
+            }
+        }
+
+        // Users lose access to values that are dropped from the map, which frees up their grants.
+        fn release_grants(&self, w: &mut Writer<K, V>, entries: Vec<Entry<V>>) {
+            if entries.is_empty() {
+                return;
+            }
+            // access that policies gave doesn't count as grants
+            let policies = self.policies.read().unwrap();
+            let mut revoked: HashMap<usize, usize> = HashMap::new();
+            for e in entries {
+                for uid in (1..e.access.len()).filter(|&uid| {
+                    e.access[uid] && !policies.allows(&policies.policies, &e.value, uid)
+                }) {
+                    *revoked.entry(uid).or_insert(0) += 1;
                 }
-                // g_map_w.refresh();
-                // b_map_w.refresh();
-                return true;
+            }
+            for (uid, revoked) in revoked {
+                self.remove_grants(w, uid, revoked);
+            }
+        }
+
+        // Free up grants that the user lost access to, wherever they are counted.
+        fn remove_grants(&self, w: &mut Writer<K, V>, uid: usize, revoked: usize) {
+            if w.limited(uid) {
+                self.quotas.lock().unwrap().remove_grants(uid, revoked);
             } else {
-                // if value exists in the global map, remove this user's name from restricted access list.
-                // otherwise, add record to the user's umap.
-                let mut res = false;
-                self.g_map_r.get_and(&k.clone(), |vs| {
-                    for val in &v {
-                        let mut last_seen = 0;
-                        let mut count = 0 as usize;
-                        // attempting to find a match for this value in the global map
-                        // _that this user does not yet have access to_. if this is successful,
-                        // indicate that the value has been found, and update access.
-                        // if not successful, insert into umap. repeat for all values.
-                        for v in vs {
-                            if *v == *val && count >= last_seen {
-                                let mut found = false;
-                                self.b_map_r.get_and(&(k.clone(), val.clone()), |s| {
-                                    // if user doesn't yet have access to a record with a matching
-                                    // value in the global map, then update this bitmap to grant
-                                    // access. otherwise, add to user map.
-                                    match get_access(&s[0][count], uid) {
-                                        true => {
-                                            last_seen = count;
-                                            count = count + 1 as usize;
-                                        }
-                                        false => {
-                                            found = true;
-                                            let mut bmaps = s[0].clone();
-                                            // println!("bmap before: {:?}", bmap[count]);
-                                            update_access(&mut bmaps[count], uid, true);
-                                            // println!("bmap after: {:?}", bmap[count]);
-                                            let bmkey = (k.clone(), val.clone());
-                                            b_map_w.update(bmkey.clone(), bmaps);
-                                            res = true;
-                                        }
-                                    }
-                                });
-                                if found {
-                                    break;
-                                }
-                            }
+                w.remove_grants(uid, revoked);
+            }
+        }
+
+        /// Use the given clock to decide when records inserted with a time-to-live expire,
+        /// instead of the system's clock.
+        pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+            *self.clock.write().unwrap() = clock;
+        }
+
+        fn now(&self) -> NaiveDateTime {
+            self.clock.read().unwrap().now().naive_utc()
+        }
+
+        /// Switch the map to partial mode, in which every key is either filled or a hole.
+        ///
+        /// Keys start out as holes. Reads of a hole miss, and call `upcall` with the key so that
+        /// the caller can fill it (see `mark_filled`). Evicting a key, or marking it as a hole,
+        /// drops its values.
+        pub fn set_partial<F>(&self, upcall: F)
+        where
+            F: Fn(&K) + Send + Sync + 'static,
+        {
+            *self.upcall.write().unwrap() = Some(Arc::new(upcall));
+            self.partial.store(true, Ordering::SeqCst);
+        }
+
+        /// Whether the map is in partial mode.
+        pub fn is_partial(&self) -> bool {
+            self.partial.load(Ordering::SeqCst)
+        }
+
+        /// Whether reads of the given key miss because it is a hole.
+        pub fn is_hole(&self, k: &K) -> bool {
+            self.is_partial() && !self.shard(k).filled.contains_key(k)
+        }
+
+        /// Mark the given key as filled, so that reads of it hit even if it has no values. This
+        /// takes effect on the next refresh.
+        pub fn mark_filled(&mut self, k: K) {
+            let mut w = self.shard(&k).global_w.lock().unwrap();
+            w.marks.insert(k, true);
+        }
+
+        /// Mark the given key as a hole, and drop its values. This takes effect on the next
+        /// refresh.
+        pub fn mark_hole(&mut self, k: &K) {
+            let mut w = self.shard(k).global_w.lock().unwrap();
+            let entries = w.mark_hole(k);
+            self.release_grants(&mut w, entries);
+        }
+
+        // Apply policies added since the last refresh to every user, and every policy to users
+        // that are new or that policies know by a new id since then.
+        fn apply_policies(&self, writers: &mut [MutexGuard<Writer<K, V>>]) {
+            let added: Vec<PolicyFn<V>> = self.pending_policies.lock().unwrap().drain(..).collect();
+            let ids: HashMap<usize, usize> = self.pending_ids.lock().unwrap().drain().collect();
+            let users = *self.largest.read().unwrap();
+            let mut policies = self.policies.write().unwrap();
+            if added.is_empty() && ids.is_empty() && policies.users == users {
+                return;
+            }
+
+            let mut changed: HashSet<usize> = ids.keys().cloned().collect();
+            changed.extend(std::cmp::max(policies.users, 1)..users);
+            let old = policies.policies.len();
+            policies.policies.extend(added);
+            policies.ids.extend(ids);
+            policies.users = users;
+            if policies.policies.is_empty() {
+                return;
+            }
+            for w in writers.iter_mut() {
+                w.apply_policies(&policies, old, &changed);
+            }
+        }
+
+        // Make every write since the last refresh visible to readers.
+        fn publish(&self, writers: &mut [MutexGuard<Writer<K, V>>]) {
+            let now = self.now();
+            for w in writers.iter_mut() {
+                let reaped = w.reap(now);
+                self.release_grants(w, reaped);
+            }
+            self.evict(writers);
+            self.apply_policies(writers);
+
+            let mut dirty: Vec<K> = Vec::new();
+            for w in writers.iter_mut() {
+                dirty.extend(w.dirty.drain());
+            }
+            let mut subscribers = self.subscribers.lock().unwrap();
+
+            // remember what each subscriber could see of the changed keys before the refresh, as
+            // of when the current version was published, so that values that have expired since
+            // are reported as lost
+            let seen = self.history.read().unwrap().published.unwrap_or(now);
+            let before: Vec<Vec<Vec<V>>> = subscribers
+                .iter()
+                .map(|s| {
+                    dirty
+                        .iter()
+                        .map(|k| {
+                            self.visible_and_at(k, s.uid, Some(seen), |vs| vs.cloned().collect())
+                                .unwrap_or_else(Vec::new)
+                        })
+                        .collect()
+                })
+                .collect();
+
+            // readers of old versions must not observe the refresh half-way through
+            let mut history = self.history.write().unwrap();
+            let snapshots: HashMap<K, Option<Vec<Entry<V>>>> = if history.recording() {
+                dirty.iter().map(|k| (k.clone(), self.snapshot(k))).collect()
+            } else {
+                HashMap::new()
+            };
+
+            for w in writers.iter_mut() {
+                w.map_w.refresh();
+                w.pending.clear();
+                w.emptied.clear();
+                if !w.marks.is_empty() {
+                    let marks: Vec<(K, bool)> = w.marks.drain().collect();
+                    for (k, mark) in marks {
+                        if mark {
+                            w.filled_w.update(k, true);
+                        } else {
+                            w.filled_w.empty(k);
                         }
                     }
-                    // b_map_w.refresh();
-                });
-                return res;
+                    w.filled_w.refresh();
+                }
+            }
+            let dirty_users: Vec<usize> = self.private_dirty.lock().unwrap().drain().collect();
+            for uid in dirty_users {
+                self.private_map(uid).w.lock().unwrap().refresh();
             }
-        }
 
+            let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+            if history.recording() {
+                let published = history.published;
+                history.entries.push_back((version, published, snapshots));
+                history.prune();
+            }
+            history.published = Some(now);
+            drop(history);
 
-        pub fn get(&self, k: &K, uid: usize) -> Option<Vec<V>> {
-            let mut res_list = Vec::new();
-            let mut seen_so_far: HashMap<(K, V), i32> = HashMap::new();
-            self.g_map_r.get_and(&k, |set| {
-                let mut increment_seen_so_far = false;
-                let mut past_count = 0;
-                for v in set {
-                    match seen_so_far.get(&(k.clone(), v.clone())) {
-                        Some(count) => {
-                            past_count = count.clone();
-                            match self
-                                .b_map_r
-                                .get_and(&(k.clone(), v.clone()), |s| s[0].clone())
-                            {
-                                Some(bmap) => {
-                                    if get_access(&bmap[count.clone() as usize], uid) {
-                                        res_list.push(v.clone());
-                                        increment_seen_so_far = true;
-                                    }
-                                }
-                                None => {}
-                            }
-                        },
-                        None => {
-                            match self
-                                .b_map_r
-                                .get_and(&(k.clone(), v.clone()), |s| s[0].clone())
-                            {
-                                Some(bmap) => {
-                                    if get_access(&bmap[0], uid) {
-                                        res_list.push(v.clone());
-                                        increment_seen_so_far = true;
-                                    }
-                                }
-                                None => {}
+            let mut before = before.into_iter();
+            subscribers.retain(|s| {
+                let mut updates: HashMap<K, Vec<(V, bool)>> = HashMap::new();
+                for (k, mut old) in dirty.iter().zip(before.next().unwrap()) {
+                    let mut changes = Vec::new();
+                    for v in self.get(k, s.uid).unwrap_or_else(Vec::new) {
+                        match old.iter().position(|o| *o == v) {
+                            Some(j) => {
+                                old.swap_remove(j);
                             }
+                            None => changes.push((v, true)),
                         }
                     }
+                    changes.extend(old.into_iter().map(|v| (v, false)));
+                    if !changes.is_empty() {
+                        updates.insert(k.clone(), changes);
+                    }
+                }
 
-                    seen_so_far.insert((k.clone(), v.clone()), past_count + 1);
+                for &(ref k, ref v) in &s.private {
+                    updates
+                        .entry(k.clone())
+                        .or_insert_with(Vec::new)
+                        .push((v.clone(), true));
                 }
+
+                // drop subscribers that have hung up
+                updates.into_iter().all(|update| s.tx.send(update).is_ok())
             });
+            for s in subscribers.iter_mut() {
+                s.private.clear();
+            }
+        }
+
+        /// The version of the map published by the latest refresh.
+        ///
+        /// Versions start at 0, and every refresh increments the version by one.
+        pub fn version(&self) -> usize {
+            self.version.load(Ordering::SeqCst)
+        }
 
-            return Some(res_list);
+        /// Set how many refreshes worth of history to retain for `get_as_of`.
+        pub fn set_history_window(&self, window: usize) {
+            let mut history = self.history.write().unwrap();
+            history.window = window;
+            history.prune();
+        }
 
+        /// Pin the current version, so that it remains readable through `get_as_of` until it is
+        /// unpinned, regardless of the history window. Returns the pinned version.
+        pub fn pin(&self) -> usize {
+            let mut history = self.history.write().unwrap();
+            let version = self.version.load(Ordering::SeqCst);
+            *history.pinned.entry(version).or_insert(0) += 1;
+            version
         }
 
-        pub fn remove(&mut self, k: &K, uid: usize) {
-            let mut seen_so_far: HashMap<(K, V), i32> = HashMap::new();
-            self.g_map_r.get_and(&k, |set| {
-                for v in set.iter() {
-                    let mut increment = false;
-                    let mut old_count = 0;
-                    let bm_key = &(k.clone(), v.clone());
-                    let mut bmap = self.b_map_r.get_and(&bm_key.clone(), |s| s[0].clone());
-                    match seen_so_far.get(bm_key) {
-                        Some(count) => {
-                            old_count = count.clone();
-                            match bmap {
-                                Some(mut bm) => {
-                                    if bm.len() > (count.clone() as usize) {
-                                        update_access(&mut bm[count.clone() as usize], uid, false);
-                                        increment = true;
-                                    }
-                                }
-                                None => {}
-                            }
-                        },
-                        None => {
-                            match bmap {
-                                Some(mut bm) => {
-                                    if bm.len() > 0 {
-                                        update_access(&mut bm[0], uid, false);
-                                        increment = true;
-                                    }
-                                }
-                                None => {}
-                            }
+        /// Release a pin previously taken with `pin`.
+        pub fn unpin(&self, version: usize) {
+            let mut history = self.history.write().unwrap();
+            let unpinned = match history.pinned.get_mut(&version) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                None => false,
+            };
+            if unpinned {
+                history.pinned.remove(&version);
+                history.prune();
+            }
+        }
+
+        // Every occurrence of every value under the given key, including expired ones, or `None`
+        // if the key isn't in the map.
+        fn snapshot(&self, k: &K) -> Option<Vec<Entry<V>>> {
+            self.shard(k).map_r.get_and(k, |set| set.to_vec())
+        }
+
+        /// Get the values under the given key that the given user could see at the given version.
+        ///
+        /// Returns `None` if the version has not been published yet, or is older than the history
+        /// window retains. Only records in the shared map are versioned, and records that expire
+        /// are visible if they hadn't expired yet when the version was published.
+        pub fn get_as_of(&self, k: &K, uid: usize, version: usize) -> Option<Vec<V>> {
+            self.lookup_as_of(k, uid, version)
+                .map(|vs| vs.unwrap_or_else(Vec::new))
+        }
+
+        /// Like `get_as_of`, but returns `Some(None)` if the key wasn't in the map at the given
+        /// version.
+        pub fn lookup_as_of(&self, k: &K, uid: usize, version: usize) -> Option<Option<Vec<V>>> {
+            let history = self.history.read().unwrap();
+            let current = self.version.load(Ordering::SeqCst);
+            if version > current {
+                return None;
+            }
+
+            // the refresh that published the version right after this one must be retained, and
+            // knows when this one was published
+            let published = if version == current {
+                history.published
+            } else {
+                match history.entries.iter().find(|&&(v, _, _)| v == version + 1) {
+                    Some(&(_, published, _)) => published,
+                    None => return None,
+                }
+            };
+            // nothing has been published before the first refresh
+            let now = match published {
+                Some(published) => published,
+                None => return Some(None),
+            };
+
+            // the key looks like it did just before the first later refresh that changed it
+            let snapshot = history
+                .entries
+                .iter()
+                .filter(|&&(v, _, _)| v > version)
+                .filter_map(|&(_, _, ref keys)| keys.get(k))
+                .next()
+                .map(|entries| entries.as_ref());
+            let visible = |e: &&Entry<V>| e.is_live(now) && get_access(&e.access, uid);
+            match snapshot {
+                Some(entries) => Some(entries.map(|entries| {
+                    entries
+                        .iter()
+                        .filter(visible)
+                        .map(|e| e.value.clone())
+                        .collect()
+                })),
+                None => Some(self.shard(k).map_r.get_and(k, |set| {
+                    set.iter().filter(visible).map(|e| e.value.clone()).collect()
+                })),
+            }
+        }
+
+        /// Subscribe the given user to changes to the records it can see.
+        ///
+        /// See `Update` for what is sent on every refresh.
+        pub fn subscribe(&self, uid: usize) -> mpsc::Receiver<Update<K, V>> {
+            let (tx, rx) = mpsc::channel();
+            self.subscribers.lock().unwrap().push(Subscriber {
+                uid: uid,
+                tx: tx,
+                private: Vec::new(),
+            });
+            rx
+        }
+
+        // The private map of the given user, which is created if it doesn't exist yet.
+        fn private_map(&self, uid: usize) -> PrivateMap<K, V> {
+            if let Some(pm) = self.private.read().unwrap().get(&uid) {
+                return pm.clone();
+            }
+            self.private
+                .write()
+                .unwrap()
+                .entry(uid)
+                .or_insert_with(|| {
+                    let (r, w) = evmap::new();
+                    PrivateMap {
+                        r: r,
+                        w: Arc::new(Mutex::new(w)),
+                    }
+                })
+                .clone()
+        }
+
+        /// A read handle for the records that only the given user can see.
+        pub fn private_reader(&self, uid: usize) -> evmap::ReadHandle<K, V> {
+            self.private_map(uid).r
+        }
+
+        /// Add a record that only the given user can see, because the global map has no record
+        /// for it to be given access to. The record becomes visible on the next refresh.
+        ///
+        /// Fails if the record would take the user over its quota.
+        pub fn insert_private(&self, uid: usize, k: K, v: V) -> Result<(), QuotaError> {
+            let mut quotas = self.quotas.lock().unwrap();
+            self.insert_private_into(&mut quotas, uid, k, v)
+        }
+
+        fn insert_private_into(
+            &self,
+            quotas: &mut Quotas<K, V>,
+            uid: usize,
+            k: K,
+            v: V,
+        ) -> Result<(), QuotaError> {
+            quotas.add_private(uid, &k, &v)?;
+            self.private_map(uid)
+                .w
+                .lock()
+                .unwrap()
+                .insert(k.clone(), v.clone());
+            self.private_dirty.lock().unwrap().insert(uid);
+            self.notify_private(uid, k, v);
+            Ok(())
+        }
+
+        // Tell the given user's subscribers about a record that was added to its private map.
+        // The change is sent along with the changes made by the next refresh.
+        fn notify_private(&self, uid: usize, k: K, v: V) {
+            for s in self.subscribers.lock().unwrap().iter_mut() {
+                if s.uid == uid {
+                    s.private.push((k.clone(), v.clone()));
+                }
+            }
+        }
+
+        /// Insert values into the global map (if `uid` is 0), or give the user access to an
+        /// occurrence of each value that it can't see yet. Returns whether any value was inserted
+        /// or granted.
+        ///
+        /// If the user would go over its grant quota, nothing is granted and `false` is returned;
+        /// use `try_insert` to tell that apart from there being nothing to grant.
+        pub fn insert(&mut self, k: K, v: Vec<V>, uid: usize) -> bool {
+            self.try_insert(k, v, uid).unwrap_or(false)
+        }
+
+        /// Insert values into the global map that expire after the given time-to-live.
+        ///
+        /// Expired values are invisible to every user, and are removed by the first refresh
+        /// after they expire.
+        pub fn insert_with_ttl(&mut self, k: K, v: Vec<V>, ttl: Duration) {
+            self.g_records += v.len();
+            let expires = self.now() + ttl;
+            let mut w = self.shard(&k).global_w.lock().unwrap();
+            w.insert_global(k, v, Some(expires));
+        }
+
+        /// Like `insert`, but fails if the user would go over its grant quota, in which case none
+        /// of the values are granted.
+        pub fn try_insert(&mut self, k: K, v: Vec<V>, uid: usize) -> Result<bool, QuotaError> {
+            let mut w = self.shard(&k).global_w.lock().unwrap();
+            let global = if uid == 0 { v.len() } else { 0 };
+            let res = if w.limited(uid) {
+                let mut quotas = self.quotas.lock().unwrap();
+                {
+                    let w: &Writer<K, V> = &w;
+                    let op = Op::Insert(k.clone(), v.clone(), uid);
+                    self.check_quotas(&quotas, &|_| w, &[op])?;
+                }
+                Self::insert_into(&mut w, Some(&mut quotas), k, v, uid)
+            } else {
+                Self::insert_into(&mut w, None, k, v, uid)
+            };
+            drop(w);
+            self.g_records += global;
+            res
+        }
+
+        // The grants are counted in `quotas` if it is given, which it must be if the user has a
+        // quota, and by the shard otherwise.
+        fn insert_into(
+            w: &mut Writer<K, V>,
+            quotas: Option<&mut Quotas<K, V>>,
+            k: K,
+            v: Vec<V>,
+            uid: usize,
+        ) -> Result<bool, QuotaError> {
+            match quotas {
+                Some(quotas) => {
+                    let before = quotas.grants_left(uid);
+                    let mut grants_left = before;
+                    let res = w.insert(k, v, uid, &mut grants_left);
+                    quotas.add_grants(uid, before - grants_left);
+                    res
+                }
+                None => {
+                    let mut grants_left = usize::max_value();
+                    let res = w.insert(k, v, uid, &mut grants_left);
+                    w.add_grants(uid, usize::max_value() - grants_left);
+                    res
+                }
+            }
+        }
+
+        /// Set the quota of the given user.
+        ///
+        /// Private records added before the first quota was set on the map don't count towards
+        /// `Quota::private_bytes`.
+        pub fn set_quota(&self, uid: usize, quota: Quota)
+        where
+            K: SizeOf,
+            V: SizeOf,
+        {
+            let mut writers = self.lock_all();
+            let mut quotas = self.quotas.lock().unwrap();
+            if quotas.size_of.is_none() {
+                quotas.size_of = Some(Arc::new(|k: &K, v: &V| k.deep_size_of() + v.deep_size_of()));
+            }
+            // from now on the user's grants are counted in `quotas`, starting from what the
+            // shards have counted so far
+            if uid != 0 {
+                for w in writers.iter_mut() {
+                    if w.limited.insert(uid) {
+                        let granted = w.grants.remove(&uid).unwrap_or(0);
+                        quotas.add_grants(uid, granted);
+                    }
+                }
+            }
+            quotas.limits.insert(uid, quota);
+        }
+
+        /// Apply all of the given writes, and publish them in a single refresh.
+        ///
+        /// No other writes or refreshes can happen in between, so readers see either none or all
+        /// of them (within the limits described on `with_shards`). Returns whether each insert
+        /// inserted or granted anything, as `insert` does, and `true` for each remove.
+        ///
+        /// Fails without applying any of the writes if they would take a user over its quota.
+        pub fn apply(&mut self, ops: Vec<Op<K, V>>) -> Result<Vec<bool>, QuotaError> {
+            let mut writers = self.lock_all();
+            let mut quotas = self.quotas.lock().unwrap();
+            {
+                let ws = &writers;
+                self.check_quotas(&quotas, &|k| &ws[self.shard_index(k)], &ops)?;
+            }
+            let global: usize = ops
+                .iter()
+                .map(|op| match *op {
+                    Op::Insert(_, ref v, 0) => v.len(),
+                    Op::InsertOrPrivate(_, _, 0) => 1,
+                    _ => 0,
+                })
+                .sum();
+
+            let mut res = Vec::with_capacity(ops.len());
+            for op in ops {
+                res.push(match op {
+                    Op::Insert(k, v, uid) => {
+                        let w = &mut writers[self.shard_index(&k)];
+                        let counted = if w.limited(uid) { Some(&mut *quotas) } else { None };
+                        Self::insert_into(w, counted, k, v, uid)?
+                    }
+                    Op::InsertOrPrivate(k, v, uid) => {
+                        let w = &mut writers[self.shard_index(&k)];
+                        let (k2, v2) = (k.clone(), v.clone());
+                        let counted = if w.limited(uid) { Some(&mut *quotas) } else { None };
+                        if !Self::insert_into(w, counted, k2, vec![v2], uid)? {
+                            // the private record is published by the same refresh
+                            self.insert_private_into(&mut quotas, uid, k, v)?;
                         }
-                    };
+                        true
+                    }
+                    Op::Remove(k, uid) => {
+                        let w = &mut writers[self.shard_index(&k)];
+                        let revoked = w.remove(&k, uid);
+                        if w.limited(uid) {
+                            quotas.remove_grants(uid, revoked);
+                        } else {
+                            w.remove_grants(uid, revoked);
+                        }
+                        true
+                    }
+                });
+            }
+            // publishing releases the grants of evicted and expired values
+            drop(quotas);
+            self.publish(&mut writers);
+            drop(writers);
+            self.g_records += global;
+            Ok(res)
+        }
 
-                    if increment {
-                        seen_so_far.insert(bm_key.clone(), old_count + 1);
+        // Check that applying the given writes in order wouldn't take any user over its quota,
+        // by applying them to copies of the entries and usage they touch.
+        fn check_quotas<'w>(
+            &self,
+            quotas: &Quotas<K, V>,
+            writer: &dyn Fn(&K) -> &'w Writer<K, V>,
+            ops: &[Op<K, V>],
+        ) -> Result<(), QuotaError>
+        where
+            K: 'w,
+            V: 'w,
+        {
+            let policies = self.policies.read().unwrap();
+            let now = self.now();
+            let mut entries: HashMap<&K, (usize, Vec<Entry<V>>)> = HashMap::new();
+            let mut used: HashMap<usize, QuotaUsage> = HashMap::new();
+            for op in ops {
+                let (k, uid) = match *op {
+                    Op::Insert(ref k, _, uid)
+                    | Op::InsertOrPrivate(ref k, _, uid)
+                    | Op::Remove(ref k, uid) => (k, uid),
+                };
+                let limit = match quotas.limits.get(&uid) {
+                    Some(limit) if uid != 0 => limit,
+                    _ => continue,
+                };
+                let used = used
+                    .entry(uid)
+                    .or_insert_with(|| quotas.used.get(&uid).cloned().unwrap_or_default());
+                let &mut (published, ref mut es) =
+                    entries.entry(k).or_insert_with(|| writer(k).peek(k));
+                let grant = |es: &mut Vec<Entry<V>>, used: &mut QuotaUsage, v: &V| {
+                    let i = match grantable(es, published, v, uid, now) {
+                        Some(i) => i,
+                        None => return Ok(false),
+                    };
+                    if limit.grants.map_or(false, |l| used.grants >= l) {
+                        return Err(QuotaError::Grants);
+                    }
+                    update_access(&mut es[i].access, uid, true);
+                    used.grants += 1;
+                    Ok(true)
+                };
+                match *op {
+                    Op::Insert(_, ref v, _) => {
+                        for v in v {
+                            grant(es, used, v)?;
+                        }
+                    }
+                    Op::InsertOrPrivate(_, ref v, _) => {
+                        if !grant(es, used, v)? {
+                            quotas.add_private_to(used, uid, k, v)?;
+                        }
+                    }
+                    Op::Remove(..) => {
+                        for e in es.iter_mut() {
+                            let allowed = policies.allows(&policies.policies, &e.value, uid);
+                            if e.access.get(uid) == Some(true) && !allowed {
+                                update_access(&mut e.access, uid, false);
+                                used.grants = used.grants.saturating_sub(1);
+                            }
+                        }
                     }
                 }
-            });
+            }
+            Ok(())
+        }
+
+        // Holes read as empty, and don't call the upcall.
+        pub fn get(&self, k: &K, uid: usize) -> Option<Vec<V>> {
+            let res = self.visible_and(k, uid, |vs| vs.cloned().collect());
+            Some(res.unwrap_or_else(Vec::new))
+        }
+
+        /// Applies a function to the values under the given key that the given user can see,
+        /// and returns the result.
+        ///
+        /// Visibility is checked in place as the iterator is advanced, so values are only cloned
+        /// if `then` clones them. Returns `None` if the key is not in the global map, or, in
+        /// partial mode, if the key is a hole (in which case the upcall is called).
+        pub fn get_and<F, T>(&self, k: &K, uid: usize, then: F) -> Option<T>
+        where
+            F: FnOnce(&mut dyn Iterator<Item = &V>) -> T,
+        {
+            if self.is_hole(k) {
+                let upcall = self.upcall.read().unwrap().clone();
+                if let Some(upcall) = upcall {
+                    upcall(k);
+                }
+                return None;
+            }
+
+            let mut then = Some(then);
+            let res = self.visible_and(k, uid, |visible| (then.take().unwrap())(visible));
+            match (res, then) {
+                // a filled key hits even if it has no values
+                (None, Some(then)) if self.is_partial() => Some(then(&mut std::iter::empty())),
+                (res, _) => res,
+            }
+        }
+
+        fn visible_and<F, T>(&self, k: &K, uid: usize, then: F) -> Option<T>
+        where
+            F: FnOnce(&mut dyn Iterator<Item = &V>) -> T,
+        {
+            self.visible_and_at(k, uid, None, then)
+        }
+
+        // Like `visible_and`, but with values that expire by the given time (or by now, if it is
+        // `None`) left out.
+        fn visible_and_at<F, T>(
+            &self,
+            k: &K,
+            uid: usize,
+            now: Option<NaiveDateTime>,
+            then: F,
+        ) -> Option<T>
+        where
+            F: FnOnce(&mut dyn Iterator<Item = &V>) -> T,
+        {
+            self.shard(k).map_r.get_and(k, |set| {
+                // the clock is behind a lock, so only read it if there is something to expire
+                let now = match now {
+                    None if set.iter().any(|e| e.expires.is_some()) => Some(self.now()),
+                    now => now,
+                };
+                let mut visible = set
+                    .iter()
+                    .filter(|e| {
+                        now.map_or(true, |now| e.is_live(now)) && get_access(&e.access, uid)
+                    })
+                    .map(|e| &e.value);
+                then(&mut visible)
+            })
+        }
+
+        /// Whether the given key is in the global map.
+        pub fn contains_key(&self, k: &K) -> bool {
+            self.shard(k).map_r.contains_key(k)
+        }
+
+        pub fn remove(&mut self, k: &K, uid: usize) {
+            let mut w = self.shard(k).global_w.lock().unwrap();
+            let revoked = w.remove(k, uid);
+            self.remove_grants(&mut w, uid, revoked);
         }
 
         pub fn add_user(&mut self) -> usize {
@@ -303,34 +1651,116 @@ pub mod srmap {
             // update largest so that next ID is one higher
             let mut largest = self.largest.write().unwrap();
             *largest += 1;
+            drop(largest);
 
             return id; // return internal id
         }
 
+        /// Register a policy that gives users access to the values it allows them to see.
+        ///
+        /// A user can see a value if any policy allows it to, or if it was given access to the
+        /// value explicitly. The policy applies to existing values and users as well as to new
+        /// ones, and, like any other write, takes effect on the next refresh.
+        ///
+        /// Policies are given the user's external id (see `set_external_id`), or its internal id
+        /// if it has none. They are checked for every user whenever a value is inserted, and
+        /// against every value by the refresh after a policy or user is added, so reads don't
+        /// run them. Revoking a user's access with `remove` doesn't hide values that a policy
+        /// allows it to see.
+        pub fn add_policy<F>(&mut self, policy: F)
+        where
+            F: Fn(&V, usize) -> bool + Send + Sync + 'static,
+        {
+            self.pending_policies.lock().unwrap().push(Arc::new(policy));
+        }
+
+        /// Record the id that the application uses for the given user, such as the user id
+        /// stored in its rows.
+        ///
+        /// Policies know the user by this id from the next refresh on. Values that they allowed
+        /// the user to see by its old id stay visible to it, so the id should be set before the
+        /// user's first refresh.
+        pub fn set_external_id(&self, uid: usize, id: usize) {
+            self.id_store.write().unwrap().insert(uid, id);
+            self.pending_ids.lock().unwrap().insert(uid, id);
+        }
+
         // Get all records that a given user has access to
         pub fn get_all(&self, uid: usize) -> Option<Vec<(K, V)>> {
             let mut buffer = Vec::new();
+            let now = self.now();
+            // keep refreshes from publishing some shards but not others while they are read
+            let _history = self.history.read().unwrap();
 
-            self.g_map_r.for_each(|k, v| {
-                for val in v {
-                    buffer.push((k.clone(), val.clone()));
-                }
-            });
+            for shard in &self.shards {
+                shard.map_r.for_each(|k, v| {
+                    for e in v {
+                        if e.is_live(now) && get_access(&e.access, uid) {
+                            buffer.push((k.clone(), e.value.clone()));
+                        }
+                    }
+                });
+            }
+
+            return Some(buffer);
+        }
+    }
+
+    impl<K, V, M> SRMap<K, V, M>
+    where
+        K: Eq + Hash + Clone + std::fmt::Debug + SizeOf,
+        V: Clone + Eq + Hash + std::fmt::Debug + evmap::ShallowCopy + SizeOf,
+        M: Clone,
+    {
+        /// Report how much memory the map uses, broken down by what it is used for.
+        pub fn memory_usage(&self) -> MemoryUsage {
+            use std::mem::size_of;
 
-            println!("gmap_r len: {:?}", self.g_map_r.len());
-            println!("ALL POSTS IN MAP: {:#?}", buffer);
-            let mut to_return = Vec::new();
+            let mut usage = MemoryUsage::default();
+            let mut granted: HashMap<usize, usize> = HashMap::new();
+            for shard in &self.shards {
+                shard.map_r.for_each(|k, set| {
+                    usage.values += k.deep_size_of();
+                    for e in set {
+                        usage.values += e.value.deep_size_of();
+                        usage.bitmaps += size_of::<BitVec>()
+                            + e.access.storage().len() * size_of::<u32>();
+                        for uid in (1..e.access.len()).filter(|&uid| e.access[uid]) {
+                            *granted.entry(uid).or_insert(0) += 1;
+                        }
+                    }
+                });
+            }
+            for (&uid, count) in &granted {
+                *usage.per_user.entry(uid).or_insert(0) += (count + 7) / 8;
+            }
 
-            for (k, val) in buffer.iter() {
-                let bmkey = (k.clone(), val.clone());
-                let mut bmap = self.b_map_r.get_and(&bmkey, |s| s[0].clone()).unwrap();
-                if get_access(&bmap[0], uid) {
-                    to_return.push(bmkey);
+            for (&uid, pm) in self.private.read().unwrap().iter() {
+                let mut size = 0;
+                pm.r.for_each(|k, set| {
+                    size += k.deep_size_of();
+                    size += set.iter().map(|v| v.deep_size_of()).sum::<usize>();
+                });
+                if size > 0 {
+                    usage.private += size;
+                    *usage.per_user.entry(uid).or_insert(0) += size;
                 }
             }
 
-            return Some(to_return);
+            let ids = self.id_store.read().unwrap().len() + self.policies.read().unwrap().ids.len();
+            usage.ids = size_of::<usize>() + ids * size_of::<(usize, usize)>();
+
+            for shard in &self.shards {
+                if let Some(ref keys) = shard.global_w.lock().unwrap().keys {
+                    // every key is held twice, along with its position
+                    usage.key_index += keys
+                        .keys
+                        .iter()
+                        .map(|k| 2 * k.deep_size_of() + size_of::<usize>())
+                        .sum::<usize>();
+                }
+            }
+            usage
         }
     }
-
 }
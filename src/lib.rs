@@ -3,13 +3,13 @@
 #![feature(try_from)]
 #![feature(box_patterns)]
 
+extern crate bit_vec;
 extern crate evmap;
 extern crate serde;
 extern crate slog;
 extern crate slog_term;
 extern crate test;
 extern crate time;
-extern crate bit_vec; 
 
 #[macro_use]
 extern crate serde_derive;
@@ -17,6 +17,7 @@ extern crate arccstr;
 extern crate chrono;
 extern crate nom_sql;
 extern crate rand;
+extern crate unicode_normalization;
 
 pub mod data;
 pub mod handle;
@@ -25,11 +26,12 @@ pub mod inner;
 use handle::handle::Handle;
 use inner::srmap::SRMap;
 
-use std::collections::HashMap;
 use std::hash::Hash;
-use std::sync::{Arc, RwLock};
 
-pub use data::{DataType, Datas, Modification, Operation, Record, Records, TableOperation};
+pub use data::{
+    Agg, Clock, Collated, Collation, DataType, DataTypeError, Datas, Decimal, ManualClock,
+    Modification, Operation, Policy, Record, Records, Rounding, SystemClock, TableOperation,
+};
 
 pub fn new<K, V, M>(lock: SRMap<K, V, M>) -> Handle<K, V, M>
 where
@@ -37,7 +39,7 @@ where
     V: Clone + Eq + std::fmt::Debug + Hash + evmap::ShallowCopy,
     M: Clone,
 {
-    let umap = Arc::new(RwLock::new(HashMap::new()));
+    let umap = lock.private_reader(0);
     Handle {
         handle: lock,
         iid: 0,
@@ -52,7 +54,18 @@ where
     V: Clone + Eq + std::fmt::Debug + Hash + evmap::ShallowCopy,
     M: Clone,
 {
-    let map = SRMap::<K, V, M>::new(meta_init);
+    construct_sharded(meta_init, 1)
+}
+
+// Constructor for read/write handle tuple over a map whose keys are spread across the given
+// number of shards, each with its own write lock
+pub fn construct_sharded<K, V, M>(meta_init: M, shards: usize) -> (Handle<K, V, M>, Handle<K, V, M>)
+where
+    K: Eq + Hash + Clone + std::fmt::Debug,
+    V: Clone + Eq + std::fmt::Debug + Hash + evmap::ShallowCopy,
+    M: Clone,
+{
+    let map = SRMap::<K, V, M>::with_shards(meta_init, shards);
     let mut w_handle = new(map.clone());
     // adds user with uid 0...
     w_handle.add_user();
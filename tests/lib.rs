@@ -1,13 +1,21 @@
 #![feature(test)]
 #![feature(duration_float)]
+#![feature(try_from)]
 
+extern crate chrono;
 extern crate evmap;
+extern crate nom_sql;
 extern crate rand;
 extern crate srmap;
 extern crate test;
 extern crate time;
 
-pub use srmap::data::{DataType, Datas, Modification, Operation, Record, Records, TableOperation};
+pub use srmap::data::{
+    Agg, DataType, Datas, Modification, Operation, Policy, Record, Records, SizeOf,
+    TableOperation,
+};
+use std::convert::TryFrom;
+use std::thread;
 use test::Bencher;
 
 fn setup() -> (
@@ -101,7 +109,7 @@ fn get_posts(num: usize) -> Vec<Vec<DataType>> {
         let pid = i.into();
         let author = (0 as usize).into();
         let cid = (0 as usize).into();
-        let content: DataType = format!("post #{}", i).into();
+        let content = DataType::try_from(format!("post #{}", i)).unwrap();
         let private = (0 as usize).into();
         let anon = 1.into();
         records.push(vec![pid, cid, author, content, private, anon]);
@@ -115,7 +123,7 @@ fn _get_private_posts(num: usize, uid: usize) -> Vec<Vec<DataType>> {
         let pid = i.into();
         let author = (uid.clone() as usize).into();
         let cid = (0 as usize).into();
-        let content: DataType = format!("post #{}", (i + uid)).into();
+        let content = DataType::try_from(format!("post #{}", (i + uid))).unwrap();
         let private = (0 as usize).into();
         let anon = 1.into();
         records.push(vec![pid, cid, author, content, private, anon]);
@@ -123,6 +131,244 @@ fn _get_private_posts(num: usize, uid: usize) -> Vec<Vec<DataType>> {
     records
 }
 
+#[test]
+fn aggregate_visible_rows() {
+    let (_r, mut w) = srmap::construct::<DataType, Vec<DataType>, Option<i32>>(None);
+    let k = DataType::try_from("posts").unwrap();
+
+    let recs = get_posts(4);
+    for r in &recs {
+        w.insert(k.clone(), r.clone(), None);
+    }
+    w.refresh();
+
+    let (_id1, _r1, mut w1) = w.clone_new_user();
+    w1.insert(k.clone(), recs[1].clone(), None);
+    w1.insert(k.clone(), recs[3].clone(), None);
+    w1.refresh();
+
+    // the global universe sees every post, the user only the ones it was given access to
+    assert_eq!(w.aggregate(&k, 0, Agg::Count), Ok(Some(4.into())));
+    assert_eq!(w.aggregate(&k, 0, Agg::Sum), Ok(Some(6.into())));
+    assert_eq!(w1.aggregate(&k, 0, Agg::Count), Ok(Some(2.into())));
+    assert_eq!(w1.aggregate(&k, 0, Agg::Sum), Ok(Some(4.into())));
+    assert_eq!(w1.aggregate(&k, 0, Agg::Min), Ok(Some(1.into())));
+    assert_eq!(w1.aggregate(&k, 0, Agg::Max), Ok(Some(3.into())));
+    assert_eq!(
+        w1.aggregate(&k, 0, Agg::Avg),
+        Ok(DataType::try_from(2.0).ok())
+    );
+    assert_eq!(w1.aggregate(&DataType::Int(1), 0, Agg::Sum), Ok(None));
+
+    // summing text, or overflowing, is an error rather than a panic
+    assert_eq!(
+        w.aggregate(&k, 3, Agg::Sum),
+        Err(srmap::data::DataTypeError::WrongType {
+            expected: "numeric",
+            found: "Text",
+        })
+    );
+    assert!(w.aggregate(&k, 3, Agg::Avg).is_err());
+    assert_eq!(w.aggregate(&k, 3, Agg::Max), Ok(Some(recs[3][3].clone())));
+    let big = vec![vec![DataType::BigInt(i64::max_value())]; 2];
+    assert_eq!(
+        Agg::Sum.apply(&big, 0),
+        Err(srmap::data::DataTypeError::Overflow)
+    );
+}
+
+#[test]
+fn subscribe_to_visible_changes() {
+    let (_r, mut w) = srmap::construct::<DataType, Vec<DataType>, Option<i32>>(None);
+    let k = DataType::try_from("posts").unwrap();
+    let recs = get_posts(2);
+
+    let (id1, _r1, mut w1) = w.clone_new_user();
+    let changes = w1.subscribe(None);
+
+    // global inserts aren't visible to the user, so it isn't told about them
+    w.insert(k.clone(), recs[0].clone(), None);
+    w.insert(k.clone(), recs[1].clone(), None);
+    w.refresh();
+    assert!(changes.try_recv().is_err());
+
+    // being given access to a record is
+    w.insert(k.clone(), recs[1].clone(), Some(id1));
+    w.refresh();
+    let (key, update) = changes.try_recv().unwrap();
+    assert_eq!(key, k);
+    let records: Records = update.into();
+    assert_eq!(&*records, &[Record::Positive(recs[1].clone())]);
+    assert!(changes.try_recv().is_err());
+
+    // and so is losing it again
+    w1.remove(k.clone(), None);
+    w1.refresh();
+    let (_, update) = changes.try_recv().unwrap();
+    assert_eq!(update, vec![(recs[1].clone(), false)]);
+}
+
+#[test]
+fn get_as_of_version() {
+    let (r, mut w) = setup();
+    let k = "k".to_string();
+    let v1 = "v1".to_string();
+    let v2 = "v2".to_string();
+    // any handle can set the window, without needing to be mutable
+    r.set_history_window(2);
+
+    w.insert(k.clone(), v1.clone(), None);
+    w.refresh();
+    w.insert(k.clone(), v2.clone(), None);
+    w.refresh();
+    assert_eq!(w.version(), 2);
+
+    let sorted = |vs: Option<Vec<String>>| {
+        vs.map(|mut vs| {
+            vs.sort();
+            vs
+        })
+    };
+    assert_eq!(sorted(w.get_as_of(&k, 0)), Some(vec![]));
+    assert_eq!(sorted(w.get_as_of(&k, 1)), Some(vec![v1.clone()]));
+    assert_eq!(sorted(w.get_as_of(&k, 2)), Some(vec![v1.clone(), v2.clone()]));
+    assert_eq!(w.get_as_of(&k, 3), None);
+
+    // the oldest version falls out of the history window
+    w.refresh();
+    assert_eq!(w.get_as_of(&k, 0), None);
+    assert_eq!(sorted(w.get_as_of(&k, 1)), Some(vec![v1.clone()]));
+}
+
+#[test]
+fn read_txn_is_consistent() {
+    let (_r, mut w) = setup();
+    let r = w.clone();
+    let k1 = "k1".to_string();
+    let k2 = "k2".to_string();
+    let v1 = "v1".to_string();
+    let v2 = "v2".to_string();
+
+    w.insert(k1.clone(), v1.clone(), None);
+    w.refresh();
+
+    {
+        let txn = r.read_txn();
+        assert_eq!(txn.get_and(&k1, |vs| vs.to_vec()), Some(vec![v1.clone()]));
+
+        // writes published while the transaction is open are not visible to it
+        w.insert(k2.clone(), v2.clone(), None);
+        w.refresh();
+        assert_eq!(txn.get_and(&k2, |vs| vs.len()), None);
+        assert_eq!(r.get_and(&k2, |vs| vs.len()), Some(1));
+        assert_eq!(txn.version() + 1, r.version());
+    }
+
+    // private records are read even for keys missing from the pinned version
+    let (id1, r1, mut w1) = w.clone_new_user();
+    let k3 = "k3".to_string();
+    w1.insert(k3.clone(), v1.clone(), Some(id1));
+    w1.refresh();
+    {
+        let txn = r1.read_txn();
+        assert_eq!(txn.get_and(&k3, |vs| vs.to_vec()), Some(vec![v1.clone()]));
+        assert_eq!(txn.get_and(&"missing".to_string(), |vs| vs.len()), None);
+    }
+
+    // with the transaction gone, the old version is no longer retained
+    w.refresh();
+    assert_eq!(r.get_as_of(&k2, 1), None);
+}
+
+#[test]
+fn batch_is_atomic() {
+    let (_r, mut w) = setup();
+    let (id1, _r1, mut w1) = w.clone_new_user();
+    let k = "k".to_string();
+    let v = "v".to_string();
+
+    // an abandoned batch leaves no trace
+    let res: Result<(), Box<dyn std::error::Error>> = w.batch(|b| {
+        b.insert(k.clone(), v.clone(), None);
+        Err("abandoned".into())
+    });
+    assert_eq!(res.unwrap_err().to_string(), "abandoned");
+    w.refresh();
+    assert_eq!(w.get_and(&k, |vs| vs.len()), None);
+
+    // writes to the same key in one batch build on each other, and are published together
+    let res: Result<(), srmap::inner::srmap::QuotaError> = w.batch(|b| {
+        b.insert(k.clone(), v.clone(), None);
+        b.insert(k.clone(), v.clone(), None);
+        Ok(())
+    });
+    assert!(res.is_ok());
+    assert_eq!(w.get_and(&k, |vs| vs.len()), Some(2));
+
+    let res: Result<(), srmap::inner::srmap::QuotaError> = w.batch(|b| {
+        b.grant(k.clone(), v.clone(), id1);
+        b.grant(k.clone(), v.clone(), id1);
+        b.grant(k.clone(), v.clone(), id1);
+        Ok(())
+    });
+    assert!(res.is_ok());
+    assert_eq!(w1.get_and(&k, |vs| vs.len()), Some(2));
+
+    // private records are published by the batch's refresh too
+    let version = w1.version();
+    let res: Result<(), srmap::inner::srmap::QuotaError> = w1.batch(|b| {
+        b.insert(k.clone(), "mine".to_string(), None);
+        b.insert("k2".to_string(), v.clone(), None);
+        Ok(())
+    });
+    assert!(res.is_ok());
+    assert_eq!(w1.version(), version + 1);
+    assert_eq!(w1.get_and(&k, |vs| vs.len()), Some(3));
+    assert_eq!(w1.get_and(&"k2".to_string(), |vs| vs.len()), Some(1));
+}
+
+#[test]
+fn shards_publish_together() {
+    use std::collections::HashMap;
+
+    let (r, mut w) = srmap::construct_sharded::<String, String, Option<i32>>(None, 4);
+    let keys: Vec<String> = (0..8).map(|i| format!("k{}", i)).collect();
+
+    let writer = {
+        let keys = keys.clone();
+        thread::spawn(move || {
+            for i in 0..200 {
+                let res: Result<(), srmap::inner::srmap::QuotaError> = w.batch(|b| {
+                    for k in &keys {
+                        b.insert(k.clone(), i.to_string(), None);
+                    }
+                    Ok(())
+                });
+                res.unwrap();
+            }
+        })
+    };
+
+    // a batch spans every shard, but reads across keys never see only part of it
+    for _ in 0..200 {
+        let txn = r.read_txn();
+        let lens: Vec<_> = keys.iter().map(|k| txn.get_and(k, |vs| vs.len())).collect();
+        assert!(lens.iter().all(|len| *len == lens[0]), "{:?}", lens);
+        drop(txn);
+
+        let mut lens: HashMap<String, usize> = HashMap::new();
+        r.for_each(|k, vs| *lens.entry(k.clone()).or_insert(0) += vs.len());
+        assert!(lens.values().all(|len| Some(len) == lens.values().next()), "{:?}", lens);
+    }
+    writer.join().unwrap();
+
+    // there is always at least one shard
+    let (r, mut w) = srmap::construct_sharded::<String, String, Option<i32>>(None, 0);
+    w.insert(keys[0].clone(), "v".to_string(), None);
+    w.refresh();
+    assert_eq!(r.get_and(&keys[0], |vs| vs.len()), Some(1));
+}
+
 #[bench]
 fn bench_insert_multival(_b: &mut Bencher) {
     let (_r, mut w) = srmap::construct::<DataType, Vec<DataType>, Option<i32>>(None);
@@ -134,7 +380,7 @@ fn bench_insert_multival(_b: &mut Bencher) {
 
     let start = std::time::Instant::now();
     for (i, r) in recs.iter().enumerate() {
-        let k: DataType = format!("x{}", i % 10000).to_string().into();
+        let k = DataType::try_from(format!("x{}", i % 10000)).unwrap();
         w.insert(k.clone(), r.clone(), None);
     }
     println!(
@@ -153,7 +399,7 @@ fn bench_insert_multival(_b: &mut Bencher) {
         // make records accessible to half the users
         if i % 2 == 0 {
             for (j, r) in recs.iter().enumerate() {
-                let k: DataType = format!("x{}", j % 10000).to_string().into();
+                let k = DataType::try_from(format!("x{}", j % 10000)).unwrap();
                 /*if j % 1000 == 0 {
                     println!("u{}, {}", i, j);
                 }*/
@@ -175,7 +421,7 @@ fn bench_insert_multival(_b: &mut Bencher) {
     let mut total_reads = 0;
     for handle in &handles {
         for j in 0..100 {
-            let k: DataType = format!("x{}", j).to_string().into();
+            let k = DataType::try_from(format!("x{}", j)).unwrap();
             let _res = handle.meta_get_and(&k, |res| {
                 total_rows += res.len();
                 total_reads += 1;
@@ -191,6 +437,1088 @@ fn bench_insert_multival(_b: &mut Bencher) {
     );
 }
 
+#[bench]
+fn bench_sharded_insert_throughput(_b: &mut Bencher) {
+    let num_shards = 8;
+    let num_posts = 400000;
+
+    let recs = get_posts(num_posts as usize);
+
+    for &num_writers in &[1, 2, 4, 8] {
+        let (_r, w) =
+            srmap::construct_sharded::<DataType, Vec<DataType>, Option<i32>>(None, num_shards);
+
+        // give each writer its own handle and share of the records
+        let work: Vec<_> = (0..num_writers)
+            .map(|i| {
+                let recs: Vec<_> = recs.iter().skip(i).step_by(num_writers).cloned().collect();
+                (w.clone(), recs)
+            })
+            .collect();
+
+        let start = std::time::Instant::now();
+        let writers: Vec<_> = work
+            .into_iter()
+            .map(|(mut w, recs)| {
+                thread::spawn(move || {
+                    for r in recs {
+                        w.insert(r[0].clone(), r, None);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        println!(
+            "Inserted {} global records into {} shards with {} writers in {:?} ({:.2} inserts/sec)!",
+            recs.len(),
+            num_shards,
+            num_writers,
+            start.elapsed(),
+            recs.len() as f64 / start.elapsed().as_float_secs(),
+        );
+    }
+}
+
+#[test]
+fn sharded_map_behaves_like_unsharded() {
+    let (_r, mut w) = srmap::construct_sharded::<String, String, Option<i32>>(None, 4);
+    let (id1, _r1, w1) = w.clone_new_user();
+
+    let keys: Vec<String> = (0..32).map(|i| format!("k{}", i)).collect();
+    let v = "v".to_string();
+    for k in &keys {
+        w.insert(k.clone(), v.clone(), None);
+    }
+    w.refresh();
+    for k in keys.iter().step_by(2) {
+        w.insert(k.clone(), v.clone(), Some(id1));
+    }
+    w.refresh();
+
+    assert_eq!(w.len(), keys.len());
+    for (i, k) in keys.iter().enumerate() {
+        assert_eq!(w.get_and(k, |vs| vs.len()), Some(1));
+        assert_eq!(w1.get_and(k, |vs| vs.len()), Some(if i % 2 == 0 { 1 } else { 0 }));
+    }
+}
+
+#[test]
+fn private_reads_are_lock_free() {
+    let (_r, mut w) = setup();
+    let (id1, r1, mut w1) = w.clone_new_user();
+    let k = "k".to_string();
+    let v = "private".to_string();
+
+    // there is no global record to grant access to, so this goes to id1's private map
+    w1.insert(k.clone(), v.clone(), Some(id1));
+    assert_eq!(r1.get_and(&k, |vs| vs.len()), None);
+    w1.refresh();
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let r = r1.clone();
+            let k = k.clone();
+            thread::spawn(move || {
+                (0..100)
+                    .map(|_| r.get_and(&k, |vs| vs.to_vec()).unwrap())
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    for reader in readers {
+        for vs in reader.join().unwrap() {
+            assert_eq!(vs, vec![v.clone()]);
+        }
+    }
+    assert_eq!(w.get_and(&k, |vs| vs.len()), None);
+}
+
+#[test]
+fn get_and_checks_each_occurrence() {
+    let (_r, mut w) = setup();
+    let (id1, _r1, mut w1) = w.clone_new_user();
+    let k = "k".to_string();
+    let v = "v".to_string();
+    let v2 = "v2".to_string();
+
+    w.insert(k.clone(), v.clone(), None);
+    w.insert(k.clone(), v2.clone(), None);
+    w.insert(k.clone(), v.clone(), None);
+    w.refresh();
+    w1.insert(k.clone(), v.clone(), Some(id1));
+    w1.insert(k.clone(), v2.clone(), Some(id1));
+    w1.refresh();
+
+    let inner = &w.handle;
+    assert_eq!(inner.get_and(&k, 0, |vs| vs.count()), Some(3));
+    assert_eq!(
+        inner.get_and(&k, id1, |vs| vs.cloned().collect::<Vec<_>>()),
+        Some(vec![v.clone(), v2.clone()])
+    );
+    assert_eq!(inner.get_and(&"missing".to_string(), 0, |vs| vs.count()), None);
+}
+
+#[test]
+fn remove_then_regrant() {
+    let (_r, mut w) = setup();
+    let (id1, r1, mut w1) = w.clone_new_user();
+    let (id2, r2, mut w2) = w.clone_new_user();
+    let k = "k".to_string();
+    let v = "v".to_string();
+
+    w.insert(k.clone(), v.clone(), None);
+    w.insert(k.clone(), v.clone(), None);
+    w.refresh();
+    w1.insert(k.clone(), v.clone(), Some(id1));
+    w2.insert(k.clone(), v.clone(), Some(id2));
+    w2.insert(k.clone(), v.clone(), Some(id2));
+    w.refresh();
+    assert_eq!(r1.get_and(&k, |vs| vs.len()), Some(1));
+    assert_eq!(r2.get_and(&k, |vs| vs.len()), Some(2));
+
+    w1.remove(k.clone(), Some(id1));
+    w.refresh();
+    assert_eq!(r1.get_and(&k, |vs| vs.len()), Some(0));
+    assert_eq!(r2.get_and(&k, |vs| vs.len()), Some(2));
+
+    w1.insert(k.clone(), v.clone(), Some(id1));
+    w.refresh();
+    assert_eq!(r1.get_and(&k, |vs| vs.to_vec()), Some(vec![v.clone()]));
+    assert_eq!(w.get_and(&k, |vs| vs.len()), Some(2));
+}
+
+#[test]
+fn memory_usage_breakdown() {
+    let (_r, mut w) = srmap::construct::<DataType, Vec<DataType>, Option<i32>>(None);
+    let (id1, _r1, mut w1) = w.clone_new_user();
+    let k: DataType = 1.into();
+    let rows = get_posts(3);
+    let private: Vec<DataType> = vec![1.into(), DataType::try_from("private").unwrap()];
+
+    for r in &rows {
+        w.insert(k.clone(), r.clone(), None);
+    }
+    w.refresh();
+    w1.insert(k.clone(), rows[0].clone(), Some(id1));
+    w1.insert(k.clone(), rows[1].clone(), Some(id1));
+    w1.insert(k.clone(), private.clone(), Some(id1));
+    w.refresh();
+
+    let usage = w.memory_usage();
+    let values: usize = rows.iter().map(|r| r.deep_size_of()).sum();
+    assert_eq!(usage.values, k.deep_size_of() + values);
+    assert_eq!(usage.private, k.deep_size_of() + private.deep_size_of());
+    assert!(usage.bitmaps > 0);
+    assert_eq!(usage.per_user.len(), 1);
+    assert_eq!(usage.per_user[&id1], usage.private + 1);
+    assert_eq!(usage.key_index, 0);
+    assert_eq!(
+        usage.total(),
+        usage.values + usage.bitmaps + usage.private + usage.ids
+    );
+
+    // keys are only indexed for eviction while there is a capacity
+    w.set_capacity(Some(10));
+    let usage = w.memory_usage();
+    assert!(usage.key_index >= 2 * k.deep_size_of());
+    assert_eq!(
+        usage.total(),
+        usage.values + usage.bitmaps + usage.private + usage.ids + usage.key_index
+    );
+    w.set_capacity(None);
+    assert_eq!(w.memory_usage().key_index, 0);
+}
+
+#[test]
+fn quotas_are_enforced() {
+    use srmap::inner::srmap::{Quota, QuotaError};
+
+    let (_r, mut w) = srmap::construct::<DataType, Vec<DataType>, Option<i32>>(None);
+    let (id1, _r1, mut w1) = w.clone_new_user();
+    let k: DataType = 1.into();
+    let rows = get_posts(3);
+    for r in &rows {
+        w.insert(k.clone(), r.clone(), None);
+    }
+    w.refresh();
+
+    w.set_quota(
+        id1,
+        Quota {
+            private_records: Some(1),
+            grants: Some(2),
+            ..Quota::default()
+        },
+    );
+    assert_eq!(w1.try_insert(k.clone(), rows[0].clone(), Some(id1)), Ok(()));
+    assert_eq!(w1.try_insert(k.clone(), rows[1].clone(), Some(id1)), Ok(()));
+    assert_eq!(
+        w1.try_insert(k.clone(), rows[2].clone(), Some(id1)),
+        Err(QuotaError::Grants)
+    );
+
+    let private: Vec<DataType> = vec![2.into()];
+    assert_eq!(w1.try_insert(k.clone(), private.clone(), Some(id1)), Ok(()));
+    assert_eq!(
+        w1.try_insert(k.clone(), private.clone(), Some(id1)),
+        Err(QuotaError::PrivateRecords)
+    );
+    w.refresh();
+    assert_eq!(w1.get_and(&k, |vs| vs.len()), Some(3));
+
+    // revoking access frees up grants
+    w1.remove(k.clone(), Some(id1));
+    assert_eq!(w1.try_insert(k.clone(), rows[2].clone(), Some(id1)), Ok(()));
+
+    w.set_quota(
+        id1,
+        Quota {
+            private_bytes: Some(1),
+            ..Quota::default()
+        },
+    );
+    assert_eq!(
+        w1.try_insert(2.into(), private.clone(), Some(id1)),
+        Err(QuotaError::PrivateBytes)
+    );
+
+    // a batch that would go over quota is abandoned as a whole
+    w.set_quota(
+        id1,
+        Quota {
+            grants: Some(2),
+            ..Quota::default()
+        },
+    );
+    w.refresh();
+    let version = w1.version();
+    let visible = w1.get_and(&k, |vs| vs.len());
+    let res = w1.batch(|b| {
+        b.grant(k.clone(), rows[0].clone(), id1);
+        b.grant(k.clone(), rows[1].clone(), id1);
+        Ok(())
+    });
+    assert_eq!(res, Err(QuotaError::Grants));
+    assert_eq!(w1.version(), version);
+    w.refresh();
+    assert_eq!(w1.get_and(&k, |vs| vs.len()), visible);
+
+    // and a plain insert over quota does nothing
+    w1.insert(k.clone(), rows[0].clone(), Some(id1));
+    w1.insert(k.clone(), rows[1].clone(), Some(id1));
+    w.refresh();
+    assert_eq!(w1.get_and(&k, |vs| vs.len()), visible.map(|n| n + 1));
+
+    // grants made before a quota is set count towards it
+    let (id2, _r2, mut w2) = w.clone_new_user();
+    assert_eq!(w2.try_insert(k.clone(), rows[0].clone(), Some(id2)), Ok(()));
+    assert_eq!(w2.try_insert(k.clone(), rows[1].clone(), Some(id2)), Ok(()));
+    w.set_quota(
+        id2,
+        Quota {
+            grants: Some(2),
+            ..Quota::default()
+        },
+    );
+    assert_eq!(
+        w2.try_insert(k.clone(), rows[2].clone(), Some(id2)),
+        Err(QuotaError::Grants)
+    );
+    w2.remove(k.clone(), Some(id2));
+    assert_eq!(w2.try_insert(k.clone(), rows[2].clone(), Some(id2)), Ok(()));
+}
+
+#[test]
+fn evicted_keys_miss() {
+    let (_r, mut w) = setup();
+    let (id1, r1, mut w1) = w.clone_new_user();
+    let keys: Vec<String> = (0..10).map(|i| format!("k{}", i)).collect();
+    let v = "v".to_string();
+
+    // keys inserted before the capacity is set can be evicted too
+    for k in &keys[..5] {
+        w.insert(k.clone(), v.clone(), None);
+    }
+    w.refresh();
+    w.insert(keys[5].clone(), v.clone(), None);
+    w.set_capacity(Some(4));
+    for k in &keys[6..] {
+        w.insert(k.clone(), v.clone(), None);
+    }
+    w.refresh();
+    assert_eq!(w.len(), 4);
+
+    let present: Vec<&String> = keys.iter().filter(|k| w.contains_key(k)).collect();
+    assert_eq!(present.len(), 4);
+    for k in &keys {
+        let expected = if present.contains(&k) { Some(1) } else { None };
+        assert_eq!(w.get_and(k, |vs| vs.len()), expected);
+        assert_eq!(w.meta_get_and(k, |vs| vs.len()).unwrap().0, expected);
+    }
+
+    // a present key with nothing visible is not a miss
+    assert_eq!(r1.get_and(present[0], |vs| vs.len()), Some(0));
+    w1.insert(present[0].clone(), v.clone(), Some(id1));
+    w.refresh();
+    assert_eq!(r1.get_and(present[0], |vs| vs.len()), Some(1));
+
+    // a key dropped before a refresh has nothing left to grant access to
+    let k = present[1].clone();
+    w.mark_hole(k.clone());
+    w1.insert(k.clone(), v.clone(), Some(id1));
+    w.refresh();
+    assert!(!w.contains_key(&k));
+    assert_eq!(w.get_and(&k, |vs| vs.len()), None);
+    assert_eq!(r1.get_and(&k, |vs| vs.to_vec()), Some(vec![v.clone()]));
+    assert_eq!(w.len(), 3);
+}
+
+#[test]
+fn partial_keys_miss_until_filled() {
+    use std::sync::{Arc, Mutex};
+
+    let (_r, mut w) = setup();
+    let (_id1, r1, _w1) = w.clone_new_user();
+    let misses = Arc::new(Mutex::new(Vec::new()));
+    let m = misses.clone();
+    w.set_partial(move |k: &String| m.lock().unwrap().push(k.clone()));
+
+    let k = "k".to_string();
+    let v = "v".to_string();
+    assert_eq!(w.get_and(&k, |vs| vs.len()), None);
+    assert_eq!(*misses.lock().unwrap(), vec![k.clone()]);
+
+    // fill the key in response to the upcall
+    w.mark_filled(k.clone());
+    w.refresh();
+    assert_eq!(w.get_and(&k, |vs| vs.len()), Some(0));
+    assert_eq!(r1.get_and(&k, |vs| vs.len()), Some(0));
+    w.insert(k.clone(), v.clone(), None);
+    w.refresh();
+    assert_eq!(w.get_and(&k, |vs| vs.len()), Some(1));
+
+    // refreshes don't count as reads
+    let _updates = w.subscribe(None);
+    w.mark_hole(k.clone());
+    w.refresh();
+    assert_eq!(misses.lock().unwrap().len(), 1);
+    assert_eq!(w.get_and(&k, |vs| vs.len()), None);
+    assert_eq!(misses.lock().unwrap().len(), 2);
+
+    // eviction turns keys into holes
+    w.set_capacity(Some(0));
+    w.insert(k.clone(), v.clone(), None);
+    w.mark_filled(k.clone());
+    w.refresh();
+    assert_eq!(w.get_and(&k, |vs| vs.len()), None);
+}
+
+#[test]
+fn expired_records_are_invisible() {
+    use srmap::data::ManualClock;
+    use std::sync::Arc;
+
+    let (_r, mut w) = setup();
+    let (id1, r1, mut w1) = w.clone_new_user();
+    let clock = Arc::new(ManualClock::new(chrono::Utc::now()));
+    w.set_clock(clock.clone());
+    let k = "session".to_string();
+    let token = "token".to_string();
+    let v = "v".to_string();
+
+    w.insert_with_ttl(k.clone(), token.clone(), chrono::Duration::milliseconds(50));
+    w.insert(k.clone(), v.clone(), None);
+    w.refresh();
+    w1.insert(k.clone(), token.clone(), Some(id1));
+    w.refresh();
+    assert_eq!(w.get_and(&k, |vs| vs.len()), Some(2));
+    assert_eq!(r1.get_and(&k, |vs| vs.to_vec()), Some(vec![token.clone()]));
+    let changes = w1.subscribe(None);
+
+    clock.advance(chrono::Duration::milliseconds(50));
+    assert_eq!(w.get_and(&k, |vs| vs.to_vec()), Some(vec![v.clone()]));
+    assert_eq!(r1.get_and(&k, |vs| vs.len()), Some(0));
+
+    // expired records are reaped on refresh, which tells subscribers that they lost them, and
+    // a key with nothing left is removed
+    w.refresh();
+    assert_eq!(changes.try_recv(), Ok((k.clone(), vec![(token.clone(), false)])));
+    assert!(changes.try_recv().is_err());
+    w.insert_with_ttl(k.clone(), token.clone(), chrono::Duration::zero());
+    let k2 = "k2".to_string();
+    w.insert_with_ttl(k2.clone(), token.clone(), chrono::Duration::zero());
+    w.refresh();
+    assert_eq!(w.get_and(&k, |vs| vs.to_vec()), Some(vec![v.clone()]));
+    assert_eq!(w.get_and(&k2, |vs| vs.len()), None);
+    assert_eq!(w.len(), 1);
+
+    // reads at a version judge expiry by when that version was published
+    w.set_history_window(2);
+    w.insert_with_ttl(k.clone(), token.clone(), chrono::Duration::milliseconds(50));
+    w.refresh();
+    let version = w.version();
+    w.insert(k.clone(), v.clone(), None);
+    w.refresh();
+    clock.advance(chrono::Duration::milliseconds(50));
+    assert_eq!(w.get_and(&k, |vs| vs.len()), Some(2));
+    assert_eq!(w.get_as_of(&k, version).map(|vs| vs.len()), Some(2));
+    assert_eq!(w.get_as_of(&k, version + 1).map(|vs| vs.len()), Some(3));
+    w.refresh();
+    assert_eq!(w.get_as_of(&k, version).map(|vs| vs.len()), Some(2));
+    assert_eq!(w.get_as_of(&k, version + 1).map(|vs| vs.len()), Some(3));
+    assert_eq!(w.get_as_of(&k, version + 2).map(|vs| vs.len()), Some(2));
+}
+
+#[test]
+fn policies_set_visibility() {
+    let (_r, mut w) = srmap::construct::<DataType, Vec<DataType>, Option<i32>>(None);
+    let (id1, r1, _w1) = w.clone_new_user();
+    let k: DataType = 1.into();
+    let author_col = 1;
+    let public_col = 2;
+    let row = |author: usize, public: i32| -> Vec<DataType> {
+        vec![k.clone(), author.into(), public.into()]
+    };
+
+    w.insert(k.clone(), row(id1, 0), None);
+    w.add_policy(Policy::Any(vec![
+        Policy::ColumnIsUser(author_col),
+        Policy::ColumnEquals(public_col, 1.into()),
+    ]));
+    w.insert(k.clone(), row(id1 + 1, 1), None);
+    w.insert(k.clone(), row(id1 + 1, 0), None);
+    w.refresh();
+    assert_eq!(w.get_and(&k, |rs| rs.len()), Some(3));
+    assert_eq!(r1.get_and(&k, |rs| rs.len()), Some(2));
+
+    // users added later see what the policies allow them to
+    let (id2, r2, _w2) = w.clone_new_user();
+    assert_eq!(id2, id1 + 1);
+    w.refresh();
+    assert_eq!(r2.get_and(&k, |rs| rs.len()), Some(2));
+
+    // rows are matched against the id the application knows the user by, if it has one
+    let (_id3, r3, _w3) = w.clone_new_user();
+    r3.set_external_id(1 << 40);
+    assert_eq!(r3.get_and(&k, |rs| rs.len()), Some(0));
+    w.refresh();
+    assert_eq!(r3.get_and(&k, |rs| rs.len()), Some(1));
+    w.insert(k.clone(), row(1 << 40, 0), None);
+    w.refresh();
+    assert_eq!(r3.get_and(&k, |rs| rs.len()), Some(2));
+
+    // new policies take effect on the next refresh
+    w.add_policy(Policy::ColumnEquals(author_col, (id1 + 1).into()));
+    assert_eq!(r1.get_and(&k, |rs| rs.len()), Some(2));
+    w.refresh();
+    assert_eq!(r1.get_and(&k, |rs| rs.len()), Some(3));
+}
+
+#[test]
+fn policies_run_on_writes() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let (_r, mut w) = srmap::construct::<DataType, Vec<DataType>, Option<i32>>(None);
+    let (_id1, r1, _w1) = w.clone_new_user();
+    let k: DataType = 1.into();
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counter = runs.clone();
+    w.handle.add_policy(move |_: &Vec<DataType>, _| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        true
+    });
+    w.insert(k.clone(), vec![k.clone()], None);
+    w.refresh();
+    let (_id2, r2, _w2) = w.clone_new_user();
+    w.refresh();
+
+    // the policies set the bitmaps, so reads don't run them
+    let runs_before = runs.load(Ordering::SeqCst);
+    assert!(runs_before > 0);
+    for _ in 0..10 {
+        assert_eq!(r1.get_and(&k, |rs| rs.len()), Some(1));
+        assert_eq!(r2.get_and(&k, |rs| rs.len()), Some(1));
+    }
+    assert_eq!(runs.load(Ordering::SeqCst), runs_before);
+}
+
+#[test]
+fn fallible_conversions() {
+    use srmap::data::DataTypeError;
+
+    assert_eq!(i64::try_from(DataType::Int(-3)), Ok(-3));
+    assert_eq!(i64::try_from(&DataType::BigInt(1 << 40)), Ok(1 << 40));
+    assert_eq!(i32::try_from(DataType::BigInt(1 << 40)), Err(DataTypeError::OutOfRange));
+    assert_eq!(f64::try_from(DataType::Real(2, 500_000_000)), Ok(2.5));
+    assert_eq!(
+        i64::try_from(DataType::try_from("text").unwrap()),
+        Err(DataTypeError::WrongType {
+            expected: "integer",
+            found: "Text",
+        })
+    );
+    assert_eq!(
+        f64::try_from(DataType::None),
+        Err(DataTypeError::WrongType {
+            expected: "numeric",
+            found: "None",
+        })
+    );
+
+    assert_eq!(DataType::try_from(-2.5), Ok(DataType::Real(-2, -500_000_000)));
+    assert_eq!(DataType::try_from(std::f64::NAN), Err(DataTypeError::NotFinite));
+    assert_eq!(DataType::try_from(std::f64::INFINITY), Err(DataTypeError::NotFinite));
+    assert_eq!(DataType::try_from(1e300), Err(DataTypeError::OutOfRange));
+
+    assert!(DataType::try_from("a long string that isn't tiny").is_ok());
+    assert_eq!(DataType::try_from("nul\0"), Err(DataTypeError::InteriorNul));
+    assert_eq!(
+        DataType::try_from("a long string with a nul\0".to_string()),
+        Err(DataTypeError::InteriorNul)
+    );
+
+    let tiny = DataType::try_from("tiny").unwrap();
+    assert_eq!(String::try_from(&tiny), Ok("tiny".to_string()));
+    assert_eq!(
+        std::borrow::Cow::try_from(&tiny).map(|s| s.len()),
+        Ok(4)
+    );
+    assert_eq!(
+        String::try_from(DataType::Int(1)),
+        Err(DataTypeError::WrongType {
+            expected: "Text",
+            found: "Int",
+        })
+    );
+
+    // large sizes are promoted rather than truncated
+    assert_eq!(DataType::from(7usize), DataType::Int(7));
+    assert_eq!(DataType::from(1usize << 40), DataType::BigInt(1 << 40));
+}
+
+#[test]
+fn literal_round_trips() {
+    use nom_sql::{Literal, Real};
+    use srmap::data::DataTypeError;
+
+    let literals = vec![
+        Literal::Null,
+        Literal::Integer(-42),
+        Literal::FixedPoint(Real {
+            integral: -3,
+            fractional: 0,
+        }),
+        Literal::String("a string".to_string()),
+        Literal::Blob(vec![0, 1, 255]),
+    ];
+    for l in literals {
+        let d = DataType::try_from(&l).unwrap();
+        assert_eq!(Literal::try_from(&d), Ok(l.clone()));
+        assert_eq!(DataType::try_from(l), Ok(d));
+    }
+
+    // 1.5 and 1.05 parse to the same literal, so neither can be read back
+    let ambiguous = Real {
+        integral: 1,
+        fractional: 5,
+    };
+    assert_eq!(
+        DataType::try_from(Literal::FixedPoint(ambiguous)),
+        Err(DataTypeError::OutOfRange)
+    );
+    assert_eq!(
+        Literal::try_from(&DataType::Real(2, 500_000_000)),
+        Ok(Literal::FixedPoint(Real {
+            integral: 2,
+            fractional: 5,
+        }))
+    );
+    assert_eq!(
+        DataType::try_from(Literal::UnsignedInteger(7)),
+        Ok(DataType::BigInt(7))
+    );
+    assert_eq!(
+        DataType::try_from(Literal::UnsignedInteger(std::u64::MAX)),
+        Err(DataTypeError::OutOfRange)
+    );
+    assert_eq!(
+        DataType::try_from(Literal::Placeholder),
+        Err(DataTypeError::Placeholder)
+    );
+    assert!(DataType::try_from(Literal::CurrentDate).is_ok());
+    assert!(DataType::try_from(Literal::CurrentTime).is_ok());
+
+    let blob = DataType::from(vec![0xde, 0xad]);
+    assert_eq!(format!("{}", blob), "x'dead'");
+    assert_eq!(Vec::<u8>::try_from(&blob), Ok(vec![0xde, 0xad]));
+}
+
+#[test]
+fn additional_types() {
+    use chrono::{Duration, NaiveDate};
+    use std::collections::HashSet;
+
+    let date = NaiveDate::from_ymd_opt(2019, 3, 14).unwrap();
+    let uuid = [
+        0x12, 0x3e, 0x45, 0x67, 0xe8, 0x9b, 0x12, 0xd3, 0xa4, 0x56, 0x42, 0x66, 0x14, 0x17, 0x40,
+        0x00,
+    ];
+    let time = DataType::try_from(-(Duration::hours(25) + Duration::milliseconds(1500))).unwrap();
+
+    assert_eq!(format!("{}", DataType::from(true)), "true");
+    assert_eq!(format!("{}", DataType::from(date)), "2019-03-14");
+    assert_eq!(format!("{}", time), "-25:00:01.500000");
+    assert_eq!(
+        format!("{}", DataType::from(uuid)),
+        "123e4567-e89b-12d3-a456-426614174000"
+    );
+
+    assert_eq!(bool::try_from(DataType::Bool(true)), Ok(true));
+    assert_eq!(NaiveDate::try_from(DataType::Date(date)), Ok(date));
+    assert_eq!(
+        Duration::try_from(&time),
+        Ok(-(Duration::hours(25) + Duration::milliseconds(1500)))
+    );
+    assert_eq!(<[u8; 16]>::try_from(DataType::from(uuid)), Ok(uuid));
+
+    // every pair of values orders consistently, grouped by type
+    let values = vec![
+        DataType::Bool(false),
+        DataType::Bool(true),
+        DataType::Int(-1),
+        DataType::BigInt(7),
+        DataType::Real(2, 500_000_000),
+        DataType::try_from("text").unwrap(),
+        DataType::Date(date),
+        DataType::Date(date.succ_opt().unwrap()),
+        time.clone(),
+        DataType::Time(0),
+        DataType::Timestamp(date.and_hms_opt(0, 0, 0).unwrap()),
+        DataType::from(vec![1, 2]),
+        DataType::from(uuid),
+        DataType::None,
+    ];
+    for (i, a) in values.iter().enumerate() {
+        for (j, b) in values.iter().enumerate() {
+            assert_eq!(a.cmp(b), i.cmp(&j), "{:?} vs {:?}", a, b);
+            assert_eq!(a == b, i == j);
+        }
+    }
+
+    let set: HashSet<_> = values.iter().chain(values.iter()).cloned().collect();
+    assert_eq!(set.len(), values.len());
+}
+
+#[test]
+fn sql_comparisons() {
+    use std::cmp::Ordering;
+
+    let null = DataType::None;
+    let text = DataType::try_from("text").unwrap();
+
+    // NULL is never equal to anything, but still works as a key
+    assert_eq!(null.sql_eq(&null), None);
+    assert_eq!(null.sql_cmp(&DataType::Int(1)), None);
+    assert_eq!(DataType::Int(1).sql_eq(&null), None);
+    assert_eq!(null, DataType::None);
+
+    // numbers compare by value across types
+    assert_eq!(DataType::Int(2).sql_eq(&DataType::Real(2, 0)), Some(true));
+    assert_eq!(
+        DataType::BigInt(3).sql_cmp(&DataType::Real(2, 500_000_000)),
+        Some(Ordering::Greater)
+    );
+    assert_eq!(text.sql_cmp(&DataType::try_from("texts").unwrap()), Some(Ordering::Less));
+    assert_eq!(text.sql_eq(&DataType::Int(1)), None);
+
+    assert!(Policy::ColumnEquals(0, DataType::Real(1, 0)).allows(&[DataType::Int(1)], 0));
+    assert!(!Policy::ColumnEquals(0, DataType::None).allows(&[DataType::None], 0));
+}
+
+#[test]
+fn parse_values() {
+    use chrono::NaiveDate;
+    use nom_sql::{Literal, SqlType};
+    use srmap::data::DataTypeError;
+
+    let ts = NaiveDate::from_ymd_opt(2019, 3, 4)
+        .unwrap()
+        .and_hms_milli_opt(5, 6, 7, 0)
+        .unwrap();
+    let values = vec![
+        (DataType::Int(-12), SqlType::Int(32)),
+        (DataType::BigInt(1 << 40), SqlType::Bigint(64)),
+        (DataType::Real(-2, -10_000_000), SqlType::Double),
+        (DataType::Real(0, -5), SqlType::Real),
+        (DataType::try_from("some \"quoted\" text").unwrap(), SqlType::Text),
+        (DataType::try_from("*").unwrap(), SqlType::Varchar(10)),
+        (DataType::Timestamp(ts), SqlType::DateTime(0)),
+        (DataType::Date(ts.date()), SqlType::Date),
+        (DataType::Bool(true), SqlType::Bool),
+        (DataType::from(vec![0, 127, 255]), SqlType::Blob),
+        (DataType::None, SqlType::Int(32)),
+    ];
+    for (v, ty) in values {
+        assert_eq!(DataType::parse(&format!("{}", v), &ty), Ok(v.clone()));
+        if v != DataType::try_from("*").unwrap() {
+            assert_eq!(DataType::parse(&v.to_string(), &ty), Ok(v));
+        }
+    }
+
+    assert_eq!(
+        DataType::parse("2019-03-04T05:06:07", &SqlType::DateTime(0)),
+        Ok(DataType::Timestamp(ts))
+    );
+    assert_eq!(
+        DataType::parse("2019-03-04T06:06:07+01:00", &SqlType::DateTime(0)),
+        Ok(DataType::Timestamp(ts))
+    );
+    assert_eq!(
+        DataType::parse("1.0000000005", &SqlType::Double),
+        Ok(DataType::Real(1, 1))
+    );
+    assert_eq!(
+        DataType::parse("3000000000", &SqlType::Int(32)),
+        Err(DataTypeError::OutOfRange)
+    );
+    assert_eq!(
+        DataType::parse("-1", &SqlType::UnsignedBigint(64)),
+        Err(DataTypeError::OutOfRange)
+    );
+    assert_eq!(
+        DataType::parse("1.2.3", &SqlType::Float),
+        Err(DataTypeError::Parse { expected: "numeric" })
+    );
+    // malformed hex is an error, not a panic
+    for hex in &["x'aéa'", "x'+f'", "x'0g'", "x'abc'"] {
+        assert_eq!(
+            DataType::parse(hex, &SqlType::Blob),
+            Err(DataTypeError::Parse {
+                expected: "ByteArray"
+            })
+        );
+    }
+    assert_eq!(
+        DataType::parse("x'0aFf'", &SqlType::Blob),
+        Ok(DataType::from(vec![10, 255]))
+    );
+    let colors = SqlType::Enum(vec![Literal::String("red".to_string())]);
+    assert!(DataType::parse("red", &colors).is_ok());
+    assert!(DataType::parse("blue", &colors).is_err());
+}
+
+#[test]
+fn collated_keys() {
+    use srmap::data::{Collated, Collation};
+    use std::cmp::Ordering;
+    use std::collections::BTreeSet;
+
+    let name = |s: &str, c| Collated::new(DataType::try_from(s).unwrap(), c);
+    let (_r, mut w) = srmap::construct::<Collated, Vec<DataType>, Option<i32>>(None);
+    let alice = name("Alice", Collation::AsciiCaseInsensitive);
+    w.insert(alice.clone(), vec![1.into()], None);
+    w.refresh();
+
+    assert_eq!(
+        w.get_and(&name("aLICE", Collation::AsciiCaseInsensitive), |rs| rs.len()),
+        Some(1)
+    );
+    assert_eq!(w.get_and(&name("Alice", Collation::Binary), |rs| rs.len()), None);
+    assert_eq!(format!("{}", alice), "\"Alice\"");
+
+    // non-ASCII letters keep their case, but composed and decomposed accents are equal
+    let ci = Collation::AsciiCaseInsensitive;
+    assert_ne!(name("\u{c9}t\u{e9}", ci), name("\u{e9}t\u{e9}", ci));
+    let un = Collation::UnicodeNormalized;
+    assert_eq!(name("caf\u{e9}", un), name("cafe\u{301}", un));
+    assert_ne!(name("caf\u{e9}", Collation::Binary), name("cafe\u{301}", Collation::Binary));
+
+    let tags: BTreeSet<_> = ["rust", "Rust", "RUST", "go"]
+        .iter()
+        .map(|t| name(t, ci))
+        .collect();
+    assert_eq!(tags.len(), 2);
+    assert_eq!(
+        DataType::try_from("B").unwrap().cmp_collated(&DataType::try_from("a").unwrap(), ci),
+        Ordering::Greater
+    );
+}
+
+#[test]
+fn decimal_money() {
+    use nom_sql::{Literal, Real, SqlType};
+    use srmap::data::{DataTypeError, Decimal, Rounding, SystemClock};
+    use std::collections::HashSet;
+
+    let money = |s: &str| DataType::parse(s, &SqlType::Decimal(12, 2)).unwrap();
+    let price = money("19.99");
+    assert_eq!(format!("{}", price), "19.99");
+    assert_eq!(format!("{}", money("0.5")), "0.50");
+    assert_eq!(format!("{}", money("-0.125")), "-0.13");
+
+    // adding a cent a million times doesn't drift
+    let cent = money("0.01");
+    let mut total = money("0");
+    for _ in 0..1000000 {
+        total = total.checked_add(&cent).unwrap();
+    }
+    assert_eq!(total, money("10000.00"));
+    assert_eq!(format!("{}", &price * &DataType::Int(3)), "59.97");
+    assert_eq!(format!("{}", &price / &DataType::Int(3)), "6.663333");
+    assert_eq!(
+        Agg::Avg.apply(&[vec![money("1.00")], vec![money("2.00")]], 0),
+        Ok(money("1.5"))
+    );
+
+    // rounding modes
+    let d: Decimal = "2.345".parse().unwrap();
+    let round = |r| format!("{}", d.rescale(10, 2, r).unwrap());
+    assert_eq!(round(Rounding::HalfUp), "2.35");
+    assert_eq!(round(Rounding::HalfEven), "2.34");
+    assert_eq!(round(Rounding::TowardZero), "2.34");
+    assert_eq!(round(Rounding::AwayFromZero), "2.35");
+    assert_eq!(
+        d.rescale(2, 1, Rounding::HalfUp).map(|d| d.to_string()),
+        Ok("2.3".to_string())
+    );
+    assert_eq!(d.rescale(2, 2, Rounding::HalfUp), Err(DataTypeError::OutOfRange));
+    let third = Decimal::new(1, 1, 0)
+        .unwrap()
+        .checked_div(&"3".parse().unwrap(), 4, Rounding::HalfEven)
+        .unwrap();
+    assert_eq!(third.to_string(), "0.3333");
+
+    // values that are equal are the same key, whatever their scale
+    let keys: HashSet<_> = vec![money("1.5"), "1.500".parse::<Decimal>().unwrap().into()]
+        .into_iter()
+        .collect();
+    assert_eq!(keys.len(), 1);
+    assert!(money("-1.5") < money("-1.25"));
+    assert_eq!(money("2").sql_eq(&DataType::Int(2)), Some(true));
+    assert_eq!(money("2.5").sql_eq(&DataType::Real(2, 500_000_000)), Some(true));
+
+    assert_eq!(
+        Literal::try_from(money("-3.25")),
+        Ok(Literal::FixedPoint(Real {
+            integral: -3,
+            fractional: 25,
+        }))
+    );
+    assert_eq!(
+        Literal::try_from(money("1.05")),
+        Err(DataTypeError::OutOfRange)
+    );
+
+    // nom_sql can't tell 1.05 from 1.5, so only whole fixed point numbers convert
+    let whole = Real {
+        integral: -3,
+        fractional: 0,
+    };
+    assert_eq!(Decimal::try_from(&whole).map(|d| d.to_string()), Ok("-3".to_string()));
+    let ambiguous = Real {
+        integral: 1,
+        fractional: 5,
+    };
+    assert_eq!(Decimal::try_from(&ambiguous), Err(DataTypeError::OutOfRange));
+
+    let clock = SystemClock;
+    let column = SqlType::Decimal(12, 2);
+    let literal = |l: Literal| DataType::from_literal_as(&l, &column, &clock);
+    assert_eq!(literal(Literal::Integer(12)), Ok(money("12.00")));
+    assert_eq!(literal(Literal::String("1.05".to_string())), Ok(money("1.05")));
+    assert_eq!(literal(Literal::Null), Ok(DataType::None));
+    assert_eq!(
+        literal(Literal::FixedPoint(ambiguous)),
+        Err(DataTypeError::OutOfRange)
+    );
+
+    // scales beyond what a decimal can hold are rejected rather than overflowing
+    assert_eq!(
+        DataType::parse("1.5", &SqlType::Decimal(40, 39)),
+        Err(DataTypeError::OutOfRange)
+    );
+    assert_eq!(
+        d.rescale(38, 39, Rounding::HalfUp),
+        Err(DataTypeError::OutOfRange)
+    );
+
+    // dividing by a value with the largest scale
+    let one = Decimal::new(1, 1, 0).unwrap();
+    let half: Decimal = format!("0.5{}", "0".repeat(37)).parse().unwrap();
+    assert_eq!(half.scale(), 38);
+    assert_eq!(
+        one.checked_div(&half, 4, Rounding::HalfUp).map(|d| d.to_string()),
+        Ok("2.0000".to_string())
+    );
+    let tiny = Decimal::new(1, 38, 38).unwrap();
+    assert_eq!(
+        one.checked_div(&tiny, 4, Rounding::HalfUp),
+        Err(DataTypeError::Overflow)
+    );
+    assert_eq!(
+        money("1").checked_div(&DataType::from(tiny)),
+        Err(DataTypeError::Overflow)
+    );
+}
+
+#[test]
+fn utc_timestamps() {
+    use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+    use nom_sql::{Literal, SqlType};
+    use srmap::data::{DataTypeError, ManualClock};
+
+    let now = Utc.from_utc_datetime(
+        &NaiveDate::from_ymd_opt(2019, 3, 4)
+            .unwrap()
+            .and_hms_opt(23, 30, 0)
+            .unwrap(),
+    );
+    let clock = ManualClock::new(now);
+    let ts = DataType::from_literal(&Literal::CurrentTimestamp, &clock).unwrap();
+    assert_eq!(ts, DataType::TimestampTz(now));
+    assert_eq!(format!("{}", ts), "2019-03-04T23:30:00+00:00");
+    assert_eq!(
+        DataType::from_literal(&Literal::CurrentDate, &clock),
+        Ok(DataType::Date(now.naive_utc().date()))
+    );
+    clock.advance(chrono::Duration::minutes(45));
+    assert_eq!(
+        DataType::from_literal(&Literal::CurrentTime, &clock),
+        Ok(DataType::Time(15 * 60 * 1_000_000))
+    );
+
+    // offsets are converted to UTC, and RFC 3339 round-trips
+    assert_eq!(
+        DataType::parse("2019-03-05T01:30:00+02:00", &SqlType::Timestamp),
+        Ok(ts.clone())
+    );
+    assert_eq!(DataType::parse(&ts.to_string(), &SqlType::Timestamp), Ok(ts.clone()));
+    assert_eq!(DateTime::<Utc>::try_from(&ts), Ok(now));
+    assert_eq!(
+        DateTime::<Utc>::try_from(DataType::Timestamp(now.naive_utc())),
+        Err(DataTypeError::WrongType {
+            expected: "TimestampTz",
+            found: "Timestamp",
+        })
+    );
+
+    // naive timestamps are in UTC, so they compare against CURRENT_TIMESTAMP as instants
+    let created = DataType::Timestamp(now.naive_utc() - chrono::Duration::hours(1));
+    let later = DataType::from_literal(&Literal::CurrentTimestamp, &clock).unwrap();
+    assert_eq!(created.sql_cmp(&later), Some(std::cmp::Ordering::Less));
+    assert_eq!(later.sql_cmp(&created), Some(std::cmp::Ordering::Greater));
+    assert_eq!(DataType::Timestamp(now.naive_utc()), ts);
+    assert!(created < ts && ts < DataType::Timestamp(now.naive_utc() + chrono::Duration::hours(1)));
+
+    // naive timestamps are written in ISO-8601, whatever the locale
+    let naive = DataType::Timestamp(now.naive_utc());
+    assert_eq!(format!("{}", naive), "2019-03-04T23:30:00");
+    assert_eq!(DataType::parse("Mon Mar  4 23:30:00 2019", &SqlType::DateTime(0)), Ok(naive));
+}
+
+#[test]
+fn checked_arithmetic() {
+    use srmap::data::DataTypeError;
+
+    let max = DataType::Int(std::i32::MAX);
+    assert_eq!(
+        max.checked_add(&1.into()),
+        Ok(DataType::BigInt(std::i32::MAX as i64 + 1))
+    );
+    assert_eq!(DataType::Int(2).checked_mul(&3.into()), Ok(DataType::Int(6)));
+    assert_eq!(DataType::Int(7).checked_div(&2.into()), Ok(DataType::Int(3)));
+    assert_eq!(
+        DataType::BigInt(std::i64::MAX).checked_add(&1.into()),
+        Err(DataTypeError::Overflow)
+    );
+    assert_eq!(
+        DataType::Int(1).checked_div(&0.into()),
+        Err(DataTypeError::DivisionByZero)
+    );
+    assert_eq!(
+        DataType::Real(1, 0).checked_div(&DataType::Real(0, 0)),
+        Err(DataTypeError::DivisionByZero)
+    );
+    assert_eq!(
+        DataType::try_from("text").unwrap().checked_sub(&1.into()),
+        Err(DataTypeError::WrongType {
+            expected: "numeric",
+            found: "Text",
+        })
+    );
+    assert_eq!(DataType::None.checked_add(&1.into()), Ok(DataType::None));
+
+    // Reals are exact, where going through f64 would not be
+    let tenth = DataType::Real(0, 100_000_000);
+    let fifth = DataType::Real(0, 200_000_000);
+    assert_eq!(tenth.checked_add(&fifth), Ok(DataType::Real(0, 300_000_000)));
+    assert_eq!(
+        DataType::Real(123_456_789, 987_654_321).checked_sub(&DataType::Real(123_456_789, 0)),
+        Ok(DataType::Real(0, 987_654_321))
+    );
+    assert_eq!(
+        DataType::Real(-1, -500_000_000).checked_mul(&2.into()),
+        Ok(DataType::Real(-3, 0))
+    );
+    let three = DataType::Real(3, 0);
+    assert_eq!(
+        DataType::Int(1).checked_div(&three),
+        Ok(DataType::Real(0, 333_333_333))
+    );
+    assert_eq!(
+        DataType::Int(2).checked_div(&three),
+        Ok(DataType::Real(0, 666_666_667))
+    );
+    assert_eq!(
+        &DataType::Int(1) - &DataType::Real(1, 500_000_000),
+        DataType::Real(0, -500_000_000)
+    );
+}
+
+#[bench]
+fn bench_get_throughput(_b: &mut Bencher) {
+    let (_r, mut w) = srmap::construct::<DataType, Vec<DataType>, Option<i32>>(None);
+
+    let num_posts = 100000;
+    let num_reads = 1000000;
+
+    let recs = get_posts(num_posts as usize);
+    // keys too long to be stored inline as `TinyText`, so that they are `Text`
+    let keys: Vec<DataType> = (0..num_posts)
+        .map(|i| DataType::try_from(format!("the key of post #{}", i)).unwrap())
+        .collect();
+    assert!(keys.iter().all(|k| match *k {
+        DataType::Text(..) => true,
+        _ => false,
+    }));
+    for (k, r) in keys.iter().zip(&recs) {
+        w.insert(k.clone(), r.clone(), None);
+    }
+    w.refresh();
+
+    // look up both with copies of the keys that share their data with the map's, which equality
+    // can shortcut on, and with copies that don't
+    let deep: Vec<_> = keys.iter().map(|k| k.deep_clone()).collect();
+    for &(name, ref keys) in &[("shared", &keys), ("deep-cloned", &deep)] {
+        let start = std::time::Instant::now();
+        let mut found = 0;
+        for i in 0..num_reads {
+            found += w.get_and(&keys[i % keys.len()], |rs| rs.len()).unwrap_or(0);
+        }
+        assert_eq!(found, num_reads);
+        println!(
+            "Read {} {} keys in {:?} ({:.2} reads/sec)!",
+            num_reads,
+            name,
+            start.elapsed(),
+            num_reads as f64 / start.elapsed().as_float_secs(),
+        );
+    }
+}
+
 #[bench]
 fn bench_memory_usage(_b: &mut Bencher) {
     let (_r, mut w) = srmap::construct::<DataType, Vec<DataType>, Option<i32>>(None);
@@ -210,6 +1538,13 @@ fn bench_memory_usage(_b: &mut Bencher) {
         start.elapsed(),
         recs.len() as f64 / start.elapsed().as_float_secs(),
     );
+    w.refresh();
+
+    let usage = w.memory_usage();
+    let values: usize = recs.iter().map(|r| r[0].deep_size_of() + r.deep_size_of()).sum();
+    assert_eq!(usage.values, values);
+    assert_eq!(usage.private, 0);
+    println!("Global records use {:?}", usage);
 
     let mut handles = Vec::new();
 
@@ -233,6 +1568,17 @@ fn bench_memory_usage(_b: &mut Bencher) {
 
         handles.push(w1.clone());
     }
+    w.refresh();
+
+    // every record was granted to the same 1% of the users
+    let usage = w.memory_usage();
+    assert_eq!(usage.values, values);
+    assert_eq!(usage.private, 0);
+    assert_eq!(usage.per_user.len(), num_users / 100);
+    for bytes in usage.per_user.values() {
+        assert_eq!(*bytes, (recs.len() + 7) / 8);
+    }
+    println!("With {} users, the map uses {} bytes", num_users, usage.total());
 
     let start = std::time::Instant::now();
     let mut total_rows = 0;
@@ -0,0 +1,73 @@
+//! Tests of `DataType` alone, which don't use the map or threads, so that they can be run
+//! under Miri with `cargo miri test --test miri`.
+#![feature(try_from)]
+
+extern crate chrono;
+extern crate srmap;
+
+use chrono::TimeZone;
+use srmap::data::DataType;
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+
+fn hash(d: &DataType) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    d.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[test]
+fn equality_by_value() {
+    let long = "a string that is too long to be tiny";
+    let values = vec![
+        DataType::None,
+        DataType::Int(5),
+        DataType::Real(5, 1),
+        DataType::try_from("tiny").unwrap(),
+        DataType::try_from(long).unwrap(),
+        DataType::Timestamp(
+            chrono::NaiveDate::from_ymd_opt(2019, 1, 1)
+                .unwrap()
+                .and_hms_nano_opt(0, 0, 0, 42)
+                .unwrap(),
+        ),
+        DataType::from(vec![1, 2, 3]),
+        DataType::Bool(false),
+        DataType::Date(chrono::NaiveDate::from_ymd_opt(2019, 1, 1).unwrap()),
+        DataType::Time(-1),
+        DataType::from([7; 16]),
+    ];
+    for (i, a) in values.iter().enumerate() {
+        // copies that share data and copies that don't are both equal
+        assert_eq!(a, &a.clone());
+        assert_eq!(a, &a.deep_clone());
+        assert_eq!(hash(a), hash(&a.deep_clone()));
+        for (j, b) in values.iter().enumerate() {
+            assert_eq!(a == b, i == j, "{:?} vs {:?}", a, b);
+        }
+    }
+
+    assert_eq!(DataType::Int(5), DataType::BigInt(5));
+    assert_ne!(DataType::Int(5), DataType::Real(5, 0));
+    assert_ne!(DataType::from(vec![1, 2]), DataType::from(vec![1, 2, 3]));
+    assert_ne!(
+        DataType::try_from(long).unwrap(),
+        DataType::try_from(&long[1..]).unwrap()
+    );
+    assert_eq!(hash(&DataType::Int(5)), hash(&DataType::BigInt(5)));
+
+    let naive = chrono::NaiveDate::from_ymd_opt(2019, 1, 1)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    let now = chrono::Utc.from_utc_datetime(&naive);
+    assert_eq!(
+        DataType::Timestamp(now.naive_utc()),
+        DataType::TimestampTz(now)
+    );
+    assert_eq!(
+        hash(&DataType::Timestamp(now.naive_utc())),
+        hash(&DataType::TimestampTz(now))
+    );
+}